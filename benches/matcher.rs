@@ -0,0 +1,29 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use textra::config::parse_config_text;
+use textra::keyboard::find_replacement;
+
+/// A lookup walks at most the longest trigger, so its time should stay flat
+/// as the rule count grows.
+fn lookups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for count in [100, 1_000, 10_000] {
+        let rules: String = (0..count).map(|i| format!(":r{i}x => {i}\nthingNumber{i}Done => {i}\n")).collect();
+        let config = parse_config_text(&rules, "bench").unwrap();
+        let hit = format!("{} so :r{}x", "x".repeat(80), count - 1);
+        let miss = format!("{} so nothing here", "x".repeat(80));
+
+        group.bench_with_input(BenchmarkId::new("longest_match", count), &hit, |b, buffer| {
+            b.iter(|| config.matcher.longest_match(black_box(buffer)))
+        });
+        group.bench_with_input(BenchmarkId::new("find_replacement_hit", count), &hit, |b, buffer| {
+            b.iter(|| find_replacement(&config, black_box(buffer)))
+        });
+        group.bench_with_input(BenchmarkId::new("find_replacement_miss", count), &miss, |b, buffer| {
+            b.iter(|| find_replacement(&config, black_box(buffer)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookups);
+criterion_main!(benches);
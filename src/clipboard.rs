@@ -0,0 +1,99 @@
+use anyhow::Result;
+use std::{mem, ptr, slice};
+use winapi::um::winbase::{GlobalAlloc, GlobalLock, GlobalUnlock, GMEM_MOVEABLE};
+use winapi::um::winuser::{
+    CloseClipboard, CountClipboardFormats, EmptyClipboard, GetClipboardData, IsClipboardFormatAvailable,
+    OpenClipboard, SetClipboardData, CF_UNICODETEXT,
+};
+
+/// What the clipboard holds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    Empty,
+    Text(String),
+    /// An image, files or anything else that isn't text.
+    Other,
+}
+
+pub fn content() -> Content {
+    if unsafe { CountClipboardFormats() } == 0 {
+        return Content::Empty;
+    }
+    match get_text() {
+        Some(text) => Content::Text(text),
+        None => Content::Other,
+    }
+}
+
+/// Returns the clipboard text, or `None` if the clipboard is empty or holds
+/// something other than text.
+pub fn get_text() -> Option<String> {
+    unsafe {
+        if IsClipboardFormatAvailable(CF_UNICODETEXT) == 0 || OpenClipboard(ptr::null_mut()) == 0 {
+            return None;
+        }
+
+        let handle = GetClipboardData(CF_UNICODETEXT);
+        let data = if handle.is_null() {
+            ptr::null()
+        } else {
+            GlobalLock(handle) as *const u16
+        };
+
+        let text = if data.is_null() {
+            None
+        } else {
+            let len = (0..).take_while(|&i| *data.add(i) != 0).count();
+            let text = String::from_utf16_lossy(slice::from_raw_parts(data, len));
+            GlobalUnlock(handle);
+            Some(text)
+        };
+
+        CloseClipboard();
+        text
+    }
+}
+
+/// Empties the clipboard.
+pub fn clear() -> Result<()> {
+    unsafe {
+        if OpenClipboard(ptr::null_mut()) == 0 {
+            return Err(anyhow::anyhow!("Failed to open clipboard: {}", std::io::Error::last_os_error()));
+        }
+        let emptied = EmptyClipboard();
+        CloseClipboard();
+        if emptied == 0 {
+            return Err(anyhow::anyhow!("Failed to empty clipboard: {}", std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+pub fn set_text(text: &str) -> Result<()> {
+    let wide: Vec<u16> = text.encode_utf16().chain(Some(0)).collect();
+
+    unsafe {
+        if OpenClipboard(ptr::null_mut()) == 0 {
+            return Err(anyhow::anyhow!("Failed to open clipboard: {}", std::io::Error::last_os_error()));
+        }
+        EmptyClipboard();
+
+        let handle = GlobalAlloc(GMEM_MOVEABLE, wide.len() * mem::size_of::<u16>());
+        if handle.is_null() {
+            CloseClipboard();
+            return Err(anyhow::anyhow!("Failed to allocate clipboard memory"));
+        }
+        let data = GlobalLock(handle) as *mut u16;
+        ptr::copy_nonoverlapping(wide.as_ptr(), data, wide.len());
+        GlobalUnlock(handle);
+
+        // On success the clipboard owns the allocation.
+        let result = SetClipboardData(CF_UNICODETEXT, handle);
+        CloseClipboard();
+        if result.is_null() {
+            return Err(anyhow::anyhow!("Failed to set clipboard data: {}", std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use crate::parser::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
@@ -17,8 +17,8 @@ use winapi::{
             WAIT_OBJECT_0,
         },
         winnt::{
-            FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE_LAST_WRITE, FILE_SHARE_DELETE, FILE_SHARE_READ,
-            FILE_SHARE_WRITE,
+            FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_WRITE,
+            FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
         },
     },
 };
@@ -26,12 +26,87 @@ use std::os::windows::ffi::OsStrExt;
 use super::*;
 
 const CONFIG_FILE_NAME: &str = "config.textra";
+const CONFIG_ENV_VAR: &str = "TEXTRA_CONFIG";
+const LOCATION_FILE_NAME: &str = "config_location";
 
-pub fn load_config() -> Result<TextraConfig, ParseError> {
-    let config_path = get_config_path().unwrap();
-    let config_str = fs::read_to_string(&config_path)
-        .expect(&format!("Failed to read config file: {:?}", config_path));
-    parse_textra_config(&config_str)
+/// Why the config couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// The file is neither UTF-8 nor UTF-16 with a byte order mark. `line`
+    /// and `byte` locate the first byte that doesn't decode.
+    Encoding { path: PathBuf, line: usize, byte: usize },
+    Parse(ParseError),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Encoding { path, line, byte } => write!(
+                f,
+                "{} isn't UTF-8 text: byte {} on line {} can't be read. Save it as UTF-8 and try again.",
+                path.display(),
+                byte,
+                line
+            ),
+            ConfigError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<ParseError> for ConfigError {
+    fn from(e: ParseError) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+pub fn load_config() -> Result<TextraConfig, ConfigError> {
+    let config_path = get_config_path()?;
+    let config_str = read_config_file(&config_path)?;
+    let mut config = parse_textra_config(&config_str)?;
+    drop_disallowed_rules(&mut config);
+    warn_expired_rules(&config);
+    Ok(config)
+}
+
+/// Removes rules whose replacement kind `allowed_replacement_types` doesn't
+/// allow, so shared setups can rule out e.g. code snippets.
+fn drop_disallowed_rules(config: &mut TextraConfig) {
+    let settings = &config.settings;
+    config.rules.retain(|rule| {
+        let allowed = settings.allows(&rule.replacement);
+        if !allowed {
+            eprintln!(
+                "rule '{}' is disabled: {} replacements are not allowed",
+                rule.triggers.join(" | "),
+                rule.replacement.kind()
+            );
+        }
+        allowed
+    });
+    config.index_triggers();
+}
+
+fn warn_expired_rules(config: &TextraConfig) {
+    let today = Local::now().date_naive();
+    for rule in &config.rules {
+        if let Some(expires) = rule.options.expires.filter(|expires| *expires < today) {
+            eprintln!(
+                "rule '{}' expired on {} and will no longer expand; you can remove it",
+                rule.triggers.join(" | "),
+                expires
+            );
+        }
+    }
 }
 
 pub fn handle_edit_config() -> Result<(), io::Error> {
@@ -54,27 +129,122 @@ pub fn handle_edit_config() -> Result<(), io::Error> {
 }
 
 pub fn display_config() {
+    let config = load_config().map_err(|e| e.to_string());
+    let name = get_config_path().unwrap().display().to_string();
+    display_rules(config, &name);
+}
+
+/// Lists the rules of a config read from `source` (see `read_config_text`).
+pub fn handle_list(source: Option<&str>) -> anyhow::Result<()> {
+    let (text, name) = read_config_text(source, io::stdin().lock())?;
+    display_rules(parse_config_text(&text, &name).map_err(|e| e.to_string()), &name);
+    Ok(())
+}
+
+/// Counts that give an overview of a config, for `textra info`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigStats {
+    pub rules: usize,
+    pub triggers: usize,
+    /// Rules by replacement kind (`simple`, `multiline`, `code`).
+    pub by_kind: BTreeMap<&'static str, usize>,
+    /// Code rules by language, lowercased.
+    pub code_by_language: BTreeMap<String, usize>,
+    pub longest_trigger: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+impl ConfigStats {
+    pub fn of(config: &TextraConfig) -> Self {
+        let mut stats = ConfigStats { rules: config.rules.len(), ..Default::default() };
+        for rule in &config.rules {
+            stats.triggers += rule.triggers.len();
+            *stats.by_kind.entry(rule.replacement.kind()).or_default() += 1;
+            if let Replacement::Code { language, .. } = &rule.replacement {
+                *stats.code_by_language.entry(language.to_lowercase()).or_default() += 1;
+            }
+            for trigger in &rule.triggers {
+                if stats.longest_trigger.as_ref().map_or(true, |longest| trigger.chars().count() > longest.chars().count()) {
+                    stats.longest_trigger = Some(trigger.clone());
+                }
+            }
+        }
+        stats.warnings = lint_config(config);
+        stats
+    }
+}
+
+pub fn handle_info(source: Option<&str>) -> anyhow::Result<()> {
+    let (text, name) = read_config_text(source, io::stdin().lock())?;
+    let config = parse_config_text(&text, &name)?;
+    let stats = ConfigStats::of(&config);
+    minimo::showln!(gray_dim, "config: ", white_bold, name);
+    minimo::showln!(gray_dim, "rules: ", yellow_bold, stats.rules.to_string(), gray_dim, format!(" ({} triggers)", stats.triggers));
+    for (kind, count) in &stats.by_kind {
+        minimo::showln!(gray_dim, format!("  {kind}: "), white_bold, count.to_string());
+    }
+    for (language, count) in &stats.code_by_language {
+        minimo::showln!(gray_dim, format!("    {language}: "), white_bold, count.to_string());
+    }
+    if let Some(longest) = &stats.longest_trigger {
+        minimo::showln!(gray_dim, "longest trigger: ", white_bold, longest, gray_dim, format!(" ({} characters)", longest.chars().count()));
+    }
+    for warning in &stats.warnings {
+        minimo::showln!(orange_bold, "warning: ", white_bold, warning);
+    }
+    Ok(())
+}
+
+/// `textra match TEXT`: shows which rule would expand at the end of `TEXT`.
+pub fn handle_match(sample: &str, source: Option<&str>) -> anyhow::Result<()> {
+    let (text, name) = read_config_text(source, io::stdin().lock())?;
+    let config = parse_config_text(&text, &name)?;
+    let report = crate::keyboard::explain_match(&config, sample);
+    if report.candidates.is_empty() {
+        minimo::showln!(gray_dim, "no trigger matches the end of ", white_bold, format!("{sample:?}"), gray_dim, ".");
+    } else {
+        minimo::showln!(gray_dim, "triggers at the end: ", white_bold, report.candidates.join(", "));
+    }
+    match report.fired {
+        Some(fired) => {
+            minimo::showln!(gray_dim, "expands: ", green_bold, fired.typed, gray_dim, format!(" (rule {})", fired.triggers.join(" | ")));
+            match fired.replacement {
+                Some(replacement) => minimo::showln!(gray_dim, "types: ", white_bold, format!("{replacement:?}")),
+                None => minimo::showln!(gray_dim, "types: ", yellow_bold, "the output of its code, which isn't run here"),
+            }
+        }
+        None if !report.candidates.is_empty() => {
+            minimo::showln!(orange_bold, "nothing expands yet", gray_dim, ": the rule waits for a commit character, the end of a word or the start of a line.");
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Pinned rules first, then by `// Order`, otherwise as written.
+fn display_order(rules: &[TextraRule]) -> Vec<&TextraRule> {
+    let mut ordered: Vec<&TextraRule> = rules.iter().collect();
+    ordered.sort_by_key(|rule| (!rule.options.pinned, rule.options.order.unwrap_or(i32::MAX)));
+    ordered
+}
+
+fn display_rules(config: Result<TextraConfig, String>, name: &str) {
     minimo::showln!(yellow_bold, "│ ", whitebg, " CONFIGURATION ");
     minimo::showln!(yellow_bold, "│ ");
-    match load_config() {
+    match config {
         Ok(config) => {
-            let config_path = get_config_path().unwrap();
             minimo::showln!(
                 yellow_bold,
                 "│ ",
                 cyan_bold,
                 "┌─ ",
                 white_bold,
-                config_path.display()
+                name
             );
             minimo::showln!(yellow_bold, "│ ", cyan_bold, "⇣ ");
             if !config.rules.is_empty() {
-                for rule in &config.rules {
-                    let (trigger, replace) = match &rule.replacement {
-                        Replacement::Simple(text) => (&rule.triggers[0], text),
-                        Replacement::Multiline(text) => (&rule.triggers[0], text),
-                        Replacement::Code { language: _, content } => (&rule.triggers[0], content),
-                    };
+                for rule in display_order(&config.rules) {
+                    let (trigger, replace) = (&rule.triggers[0], rule.shown_replacement());
                     let trimmed = minimo::text::chop(replace, 50 - trigger.len())[0].clone();
 
                     minimo::showln!(
@@ -89,6 +259,16 @@ pub fn display_config() {
                         white_bold,
                         trimmed
                     );
+                    let note: Vec<&str> = rule
+                        .options
+                        .category
+                        .as_deref()
+                        .into_iter()
+                        .chain(rule.description.as_deref().and_then(|description| description.lines().next()))
+                        .collect();
+                    if !note.is_empty() {
+                        minimo::showln!(yellow_bold, "│ ", gray_dim, format!("    {}", note.join(" · ")));
+                    }
                 }
             }
         }
@@ -104,23 +284,335 @@ pub fn display_config() {
     minimo::showln!(gray_dim, "");
 }
 
+/// Where the active config path came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Environment,
+    Migrated,
+    Documents,
+    HomeConfig,
+    LocalAppData,
+}
+
+impl ConfigSource {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            ConfigSource::Environment => "TEXTRA_CONFIG environment variable",
+            ConfigSource::Migrated => "location set by `textra config migrate`",
+            ConfigSource::Documents => "default location (documents/textra)",
+            ConfigSource::HomeConfig => "fallback location (~/.config/textra, no documents folder)",
+            ConfigSource::LocalAppData => "fallback location (local app data, no documents or home folder)",
+        }
+    }
+}
+
+/// Works out which config file is in use without creating anything.
+pub fn resolve_config_path() -> Result<(PathBuf, ConfigSource), io::Error> {
+    if let Some(path) = env::var_os(CONFIG_ENV_VAR).filter(|path| !path.is_empty()) {
+        return Ok((PathBuf::from(path), ConfigSource::Environment));
+    }
+    if let Some(path) = location_file_path().and_then(|file| recorded_config_path(&file)) {
+        return Ok((path, ConfigSource::Migrated));
+    }
+
+    default_config_file(dirs::document_dir(), dirs::home_dir(), dirs::data_local_dir()).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "Could not find a documents, home or local app data directory")
+    })
+}
+
+/// Picks the documents folder, then `~/.config`, then local app data. A
+/// location that already holds a config wins, so a fallback keeps being used
+/// once a config has been created there.
+fn default_config_file(
+    documents_dir: Option<PathBuf>,
+    home_dir: Option<PathBuf>,
+    local_data_dir: Option<PathBuf>,
+) -> Option<(PathBuf, ConfigSource)> {
+    let candidates: Vec<(PathBuf, ConfigSource)> = [
+        (documents_dir.map(|dir| dir.join("textra")), ConfigSource::Documents),
+        (home_dir.map(|dir| dir.join(".config").join("textra")), ConfigSource::HomeConfig),
+        (local_data_dir.map(|dir| dir.join("textra")), ConfigSource::LocalAppData),
+    ]
+    .into_iter()
+    .filter_map(|(dir, source)| Some((dir?.join(CONFIG_FILE_NAME), source)))
+    .collect();
+
+    candidates
+        .iter()
+        .find(|(path, _)| path.exists())
+        .or_else(|| candidates.first())
+        .cloned()
+}
+
 pub fn get_config_path() -> Result<PathBuf, io::Error> {
-    let home_dir = dirs::document_dir().unwrap();
-    let home_config_dir = home_dir.join("textra");
-    let home_config_file = home_config_dir.join(CONFIG_FILE_NAME);
+    ensure_config_path().map(|(path, _)| path)
+}
+
+/// Like `get_config_path`, but also says whether the config was created just
+/// now.
+pub fn ensure_config_path() -> Result<(PathBuf, bool), io::Error> {
+    let (config_path, source) = resolve_config_path()?;
+    let created = ensure_config_at(&config_path)?;
+    if created && !matches!(source, ConfigSource::Documents | ConfigSource::Environment | ConfigSource::Migrated) {
+        eprintln!("created config at {} ({})", config_path.display(), source.describe());
+    }
+    Ok((config_path, created))
+}
+
+/// Where `textra config migrate` records the config's new path.
+fn location_file_path() -> Option<PathBuf> {
+    crate::installer::install_dir_path().ok().map(|dir| dir.join(LOCATION_FILE_NAME))
+}
+
+/// The config path recorded in `location_file`, if there is one.
+fn recorded_config_path(location_file: &Path) -> Option<PathBuf> {
+    let text = fs::read_to_string(location_file).ok()?;
+    Some(PathBuf::from(text.trim())).filter(|path| !path.as_os_str().is_empty())
+}
+
+/// `textra config migrate PATH`: moves the config to `PATH` and uses it from
+/// there from now on. A running daemon is restarted, since it watches the
+/// old folder for changes.
+pub fn handle_migrate_config(target: &str) -> anyhow::Result<()> {
+    let (from, source) = resolve_config_path()?;
+    if source == ConfigSource::Environment {
+        minimo::showln!(
+            orange_bold,
+            "the config location is set by TEXTRA_CONFIG. point that variable at the new location instead."
+        );
+        return Ok(());
+    }
+    let location_file =
+        location_file_path().ok_or_else(|| anyhow::anyhow!("Could not find the home directory"))?;
+    let target = env::current_dir()?.join(expand_home(target, dirs::home_dir()));
+    let to = migrate_config(&from, &target, &location_file)?;
+    minimo::showln!(gray_dim, "moved the config to ", white_bold, to.display());
+    if is_service_running() {
+        handle_stop()?;
+        handle_run()?;
+    }
+    Ok(())
+}
+
+/// Replaces a leading `~` with the home directory, since the Windows shells
+/// don't.
+fn expand_home(path: &str, home_dir: Option<PathBuf>) -> PathBuf {
+    match (path.strip_prefix('~'), home_dir) {
+        (Some(rest), Some(home_dir)) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            home_dir.join(rest.trim_start_matches(['/', '\\']))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Moves the config at `from` to `target` and records its new path in
+/// `location_file`. A `target` that is a folder, or looks like one because
+/// it has no extension (`~/.config/textra`), gets `config.textra` inside it.
+/// Returns the new path.
+fn migrate_config(from: &Path, target: &Path, location_file: &Path) -> Result<PathBuf, io::Error> {
+    let to = if target.is_dir() || target.extension().is_none() {
+        target.join(CONFIG_FILE_NAME)
+    } else {
+        target.to_path_buf()
+    };
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists; move or delete it first", to.display()),
+        ));
+    }
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // The original is only removed once the pointer to the copy is written,
+    // so a failure part way leaves the config where Textra will look for it.
+    fs::copy(from, &to)?;
+    let recorded = location_file
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| write_config_atomically(location_file, &to.to_string_lossy()));
+    if let Err(e) = recorded {
+        let _ = fs::remove_file(&to);
+        return Err(e);
+    }
+    fs::remove_file(from)?;
+    Ok(to)
+}
+
+/// Writes the default config to `path` unless there is a file there already.
+/// Returns whether it did.
+fn ensure_config_at(path: &Path) -> Result<bool, io::Error> {
+    if path.exists() {
+        return Ok(false);
+    }
+    if let Some(config_dir) = path.parent() {
+        fs::create_dir_all(config_dir)?;
+    }
+    create_default_config(path)?;
+    Ok(true)
+}
+
+/// Creates the config if there is none yet and, if so, tells the user where
+/// it is and how to change it. The daemon calls `get_config_path` instead,
+/// which creates it silently.
+pub fn bootstrap_config() -> Result<(), io::Error> {
+    let (config_path, created) = ensure_config_path()?;
+    if created {
+        for line in first_run_message(&config_path) {
+            minimo::showln!(gray_dim, line);
+        }
+    }
+    Ok(())
+}
+
+fn first_run_message(config_path: &Path) -> Vec<String> {
+    vec![
+        format!("no config found, so a default one was created at {}", config_path.display()),
+        "add a line like `:sig => Best regards, Jane` to make a snippet,".to_string(),
+        "or `///expand_on_commit:true` to expand only after a space or punctuation.".to_string(),
+        "run `textra edit` to open it; changes apply as soon as you save.".to_string(),
+    ]
+}
+
+/// Reads a config file. Notepad's "Unicode" is UTF-16, so that is decoded
+/// too when the file starts with a byte order mark.
+pub fn read_config_file(path: &Path) -> Result<String, ConfigError> {
+    let bytes = fs::read(path)?;
+    let text = decode_config(&bytes).map_err(|byte| ConfigError::Encoding {
+        path: path.to_path_buf(),
+        line: bytes[..byte].iter().filter(|&&b| b == b'\n').count() + 1,
+        byte,
+    })?;
+    Ok(clean_config_text(&text))
+}
+
+/// Drops the byte order mark some editors put at the start of UTF-8 files,
+/// which the grammar would report as an error on line 1, and turns CRLF line
+/// endings into LF. Multiline replacements get the line endings of the
+/// `line_endings` setting after parsing either way.
+fn clean_config_text(text: &str) -> String {
+    text.strip_prefix('\u{feff}').unwrap_or(text).replace("\r\n", "\n")
+}
+
+/// Decodes UTF-16 with a byte order mark, and UTF-8 otherwise. Fails with the
+/// offset of the first byte that can't be decoded.
+fn decode_config(bytes: &[u8]) -> Result<String, usize> {
+    let utf16 = match bytes {
+        [0xFF, 0xFE, rest @ ..] => Some((rest, u16::from_le_bytes as fn([u8; 2]) -> u16)),
+        [0xFE, 0xFF, rest @ ..] => Some((rest, u16::from_be_bytes as fn([u8; 2]) -> u16)),
+        _ => None,
+    };
+    match utf16 {
+        Some((rest, to_unit)) => {
+            let units = rest.chunks(2).map(|pair| match pair {
+                [a, b] => to_unit([*a, *b]),
+                // An odd byte at the end can't be a whole character.
+                _ => 0xDC00,
+            });
+            let mut text = String::with_capacity(rest.len() / 2);
+            let mut offset = 2;
+            for unit in char::decode_utf16(units) {
+                let c = unit.map_err(|_| offset)?;
+                offset += c.len_utf16() * 2;
+                text.push(c);
+            }
+            Ok(text)
+        }
+        None => String::from_utf8(bytes.to_vec()).map_err(|e| e.utf8_error().valid_up_to()),
+    }
+}
 
-    if home_config_file.exists() {
-        return Ok(home_config_file);
+/// Reads config text for a command: `-` reads `stdin`, any other value is a
+/// file path, and `None` is the usual config file. Also returns a name for
+/// the source to use in messages.
+pub fn read_config_text(source: Option<&str>, mut stdin: impl io::Read) -> anyhow::Result<(String, String)> {
+    match source {
+        Some("-") => {
+            let mut text = String::new();
+            stdin.read_to_string(&mut text)?;
+            Ok((clean_config_text(&text), "<stdin>".to_string()))
+        }
+        Some(path) => Ok((read_config_file(Path::new(path))?, path.to_string())),
+        None => {
+            let path = get_config_path()?;
+            Ok((read_config_file(&path)?, path.display().to_string()))
+        }
     }
+}
 
-    fs::create_dir_all(&home_config_dir)?;
-    let home_config_file = home_config_dir.join(CONFIG_FILE_NAME);
-    create_default_config(&home_config_file)?;
-    Ok(home_config_file)
+pub fn parse_config_text(text: &str, name: &str) -> anyhow::Result<TextraConfig> {
+    parse_textra_config(text).map_err(|e| anyhow::anyhow!("{}", e.with_path(name)))
+}
+
+/// Checks a config and reports the first problem, with its line and column.
+pub fn handle_validate(source: Option<&str>) -> anyhow::Result<()> {
+    let (text, name) = read_config_text(source, io::stdin().lock())?;
+    match parse_config_text(&text, &name) {
+        Ok(config) => {
+            minimo::showln!(gray_dim, "config is ", green_bold, "valid", gray_dim, format!(" ({} rules)", config.rules.len()));
+            for warning in lint_config(&config) {
+                minimo::showln!(orange_bold, "warning: ", white_bold, warning);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            minimo::showln!(red_bold, "config is invalid:");
+            eprintln!("{}", e);
+            Err(anyhow::anyhow!("invalid config: {}", name))
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DYNAMIC_TOKEN: regex::Regex = regex::Regex::new(r"\{\{[^{}]+\}\}").unwrap();
+}
+
+/// Things that parse but probably don't do what was meant.
+pub fn lint_config(config: &TextraConfig) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for rule in &config.rules {
+        if let Replacement::Code { content, .. } = &rule.replacement {
+            if let Some(token) = DYNAMIC_TOKEN.find(content) {
+                warnings.push(format!(
+                    "'{}' is a code rule, and tokens like {} are only filled in for text rules; the code's output is typed as-is. Work the value out in the code instead.",
+                    rule.triggers[0],
+                    token.as_str()
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+pub fn display_config_location() -> Result<(), io::Error> {
+    let (config_path, source) = resolve_config_path()?;
+    minimo::showln!(gray_dim, "config: ", white_bold, config_path.display());
+    minimo::showln!(gray_dim, "source: ", yellow_bold, source.describe());
+    match fs::metadata(&config_path) {
+        Ok(metadata) => {
+            minimo::showln!(gray_dim, "exists: ", green_bold, format!("yes ({} bytes)", metadata.len()));
+        }
+        Err(_) => {
+            minimo::showln!(gray_dim, "exists: ", orange_bold, "no (a default config is created on first use)");
+        }
+    }
+    Ok(())
 }
 
 pub fn create_default_config(path: &Path) -> Result<(), io::Error> {
-    fs::write(path, DEFAULT_CONFIG).expect("Failed to write default config file");
+    write_config_atomically(path, DEFAULT_CONFIG)
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so
+/// the daemon never reads a half-written config.
+pub fn write_config_atomically(path: &Path, contents: &str) -> Result<(), io::Error> {
+    use std::io::Write;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents.as_bytes())?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
     Ok(())
 }
 
@@ -158,7 +650,8 @@ pub fn watch_config(sender: std::sync::mpsc::Sender<Message>) -> Result<(), io::
                 buffer.as_mut_ptr() as LPVOID,
                 buffer.len() as DWORD,
                 FALSE,
-                FILE_NOTIFY_CHANGE_LAST_WRITE,
+                // Atomic saves rename a finished file over the config.
+                FILE_NOTIFY_CHANGE_LAST_WRITE | FILE_NOTIFY_CHANGE_FILE_NAME,
                 &mut bytes_returned,
                 &mut overlapped,
                 None,
@@ -191,6 +684,8 @@ btw => by the way
 
 :email => example@example.com
 
+// Secret
+// Confirm
 :psswd => 0nceUpon@TimeInPluto
 
 pfa => please find the attached information as requested
@@ -201,4 +696,273 @@ pftb => please find the below information as required
 
 ccc => continue writing complete code without skipping anything
 
-"#;
\ No newline at end of file
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf16_config_is_decoded() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = "///cancel_key:f12\r\n:café => naïve ☕\r\n";
+
+        let little_endian = dir.path().join("le.textra");
+        let bytes: Vec<u8> = [0xFF, 0xFE].into_iter().chain(text.encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        fs::write(&little_endian, bytes).unwrap();
+        assert_eq!(read_config_file(&little_endian).unwrap(), text);
+
+        let big_endian = dir.path().join("be.textra");
+        let bytes: Vec<u8> = [0xFE, 0xFF].into_iter().chain(text.encode_utf16().flat_map(u16::to_be_bytes)).collect();
+        fs::write(&big_endian, bytes).unwrap();
+        assert_eq!(read_config_file(&big_endian).unwrap(), text);
+    }
+
+    #[test]
+    fn test_bom_and_crlf_are_cleaned_up() {
+        let clean = "///cancel_key:f12\n// Category: Chat\nbtw => by the way\n:sig => `Best,\nJohn`\n";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        fs::write(&path, format!("\u{feff}{}", clean.replace('\n', "\r\n"))).unwrap();
+
+        let text = read_config_file(&path).unwrap();
+        assert_eq!(text, clean);
+        let (parsed, expected) = (parse_textra_config(&text).unwrap(), parse_textra_config(clean).unwrap());
+        assert_eq!(parsed.rules, expected.rules);
+        assert_eq!(parsed.settings, expected.settings);
+    }
+
+    #[test]
+    fn test_latin1_config_is_an_encoding_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        // "caf\xe9" is "café" in Latin-1.
+        fs::write(&path, b"btw => by the way\n:cafe => caf\xe9\n").unwrap();
+
+        match read_config_file(&path) {
+            Err(ConfigError::Encoding { line, byte, .. }) => assert_eq!((line, byte), (2, 30)),
+            other => panic!("expected an encoding error, got {:?}", other),
+        }
+        let message = read_config_file(&path).unwrap_err().to_string();
+        assert!(message.contains("line 2") && message.contains("UTF-8"), "{message}");
+    }
+
+    #[test]
+    fn test_first_run_creates_config_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("textra").join(CONFIG_FILE_NAME);
+
+        assert!(ensure_config_at(&path).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), DEFAULT_CONFIG);
+        let message = first_run_message(&path).join("\n");
+        assert!(message.contains(&path.display().to_string()));
+        assert!(message.contains("textra edit"));
+
+        fs::write(&path, "btw => by the way\n").unwrap();
+        assert!(!ensure_config_at(&path).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "btw => by the way\n");
+    }
+
+    #[test]
+    fn test_validate_config_from_stdin() {
+        let stdin = io::Cursor::new("btw => by the way\n:email => a@xo.rs\n");
+        let (text, name) = read_config_text(Some("-"), stdin).unwrap();
+        assert_eq!(name, "<stdin>");
+        assert_eq!(parse_config_text(&text, &name).unwrap().rules.len(), 2);
+
+        let stdin = io::Cursor::new("btw by the way\n");
+        let (text, name) = read_config_text(Some("-"), stdin).unwrap();
+        let error = parse_config_text(&text, &name).unwrap_err().to_string();
+        assert!(error.contains("<stdin>"), "{error}");
+    }
+
+    #[test]
+    fn test_display_order_puts_pinned_and_ordered_rules_first() {
+        let config = parse_textra_config(
+            "a => 1
+// Order: 2
+b => 2
+// Pinned
+c => 3
+d => 4
+// Order: 1
+e => 5
+// Pinned
+// Order: 9
+f => 6
+",
+        )
+        .unwrap();
+        let triggers: Vec<&str> = display_order(&config.rules).iter().map(|rule| rule.triggers[0].as_str()).collect();
+        assert_eq!(triggers, vec!["f", "c", "e", "b", "a", "d"]);
+    }
+
+    #[test]
+    fn test_secret_rules_are_masked() {
+        let config = parse_textra_config("// Secret\n:psswd => hunter2\n:email => a@xo.rs\n").unwrap();
+        assert_eq!(config.rules[0].shown_replacement(), "***");
+        assert_eq!(config.rules[1].shown_replacement(), "a@xo.rs");
+        // The rule itself keeps its replacement, so it still expands.
+        assert_eq!(config.rules[0].replacement, Replacement::Simple("hunter2".to_string()));
+    }
+
+    #[test]
+    fn test_config_stats() {
+        let config = parse_textra_config(
+            "btw | :btw => by the way\n:sig => `Best,\nJohn`\n:now => ```python\nprint(\"{{time}}\")\n```\n:uuid => ```Python\nprint(1)\n```\n:d => ```javascript\nreturn 1;\n```\n",
+        )
+        .unwrap();
+        let stats = ConfigStats::of(&config);
+
+        assert_eq!(stats.rules, 5);
+        assert_eq!(stats.triggers, 6);
+        assert_eq!(stats.by_kind, BTreeMap::from([("code", 3), ("multiline", 1), ("simple", 1)]));
+        assert_eq!(stats.code_by_language, BTreeMap::from([("javascript".to_string(), 1), ("python".to_string(), 2)]));
+        assert_eq!(stats.longest_trigger.as_deref(), Some(":uuid"));
+        assert_eq!(stats.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_lint_warns_about_tokens_in_code() {
+        let config = parse_textra_config(
+            ":today => {{date}}\n:stamp => ```python\nprint(\"{{date}}\")\n```\n:ok => ```python\nprint(1)\n```\n",
+        )
+        .unwrap();
+        let warnings = lint_config(&config);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains(":stamp") && warnings[0].contains("{{date}}"), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn test_write_config_atomically_replaces_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        let small = "btw => by the way\n".to_string();
+        let large = small.repeat(10_000);
+        write_config_atomically(&path, &small).unwrap();
+
+        let reader = {
+            let path = path.clone();
+            let (small, large) = (small.clone(), large.clone());
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    let contents = fs::read_to_string(&path).unwrap_or_else(|_| small.clone());
+                    assert!(contents == small || contents == large, "saw a partial config");
+                }
+            })
+        };
+        for i in 0..20 {
+            write_config_atomically(&path, if i % 2 == 0 { &large } else { &small }).unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), small);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_disallowed_replacement_types_are_dropped() {
+        let input = "///allowed_replacement_types:simple\nbtw => by the way\n:date => ```python\nprint(1)\n```\n";
+        let mut config = parse_textra_config(input).expect("Failed to parse config");
+        drop_disallowed_rules(&mut config);
+
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.rules[0].triggers, vec!["btw".to_string()]);
+    }
+
+    #[test]
+    fn test_all_replacement_types_are_allowed_by_default() {
+        let input = "btw => by the way\n:date => ```python\nprint(1)\n```\n";
+        let mut config = parse_textra_config(input).expect("Failed to parse config");
+        drop_disallowed_rules(&mut config);
+
+        assert_eq!(config.rules.len(), 2);
+    }
+
+    #[test]
+    fn test_default_config_file_prefers_documents() {
+        let (path, source) = default_config_file(
+            Some(PathBuf::from("C:/docs")),
+            Some(PathBuf::from("C:/home")),
+            Some(PathBuf::from("C:/local")),
+        )
+        .unwrap();
+
+        assert_eq!(path, PathBuf::from("C:/docs").join("textra").join(CONFIG_FILE_NAME));
+        assert_eq!(source, ConfigSource::Documents);
+    }
+
+    #[test]
+    fn test_default_config_file_falls_back_without_documents() {
+        let (path, source) =
+            default_config_file(None, Some(PathBuf::from("C:/home")), Some(PathBuf::from("C:/local"))).unwrap();
+        assert_eq!(path, PathBuf::from("C:/home").join(".config").join("textra").join(CONFIG_FILE_NAME));
+        assert_eq!(source, ConfigSource::HomeConfig);
+
+        let (_, source) = default_config_file(None, None, Some(PathBuf::from("C:/local"))).unwrap();
+        assert_eq!(source, ConfigSource::LocalAppData);
+
+        assert!(default_config_file(None, None, None).is_none());
+    }
+
+    #[test]
+    fn test_default_config_file_keeps_using_existing_fallback() {
+        let home = tempfile::tempdir().unwrap();
+        let config_dir = home.path().join(".config").join("textra");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(config_dir.join(CONFIG_FILE_NAME), "btw => by the way\n").unwrap();
+
+        let (_, source) = default_config_file(
+            Some(PathBuf::from("C:/does-not-exist")),
+            Some(home.path().to_path_buf()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(source, ConfigSource::HomeConfig);
+    }
+
+    #[test]
+    fn test_migrate_config_records_new_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("documents").join(CONFIG_FILE_NAME);
+        let location_file = dir.path().join(".textra").join(LOCATION_FILE_NAME);
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, "btw => by the way\n").unwrap();
+        assert_eq!(recorded_config_path(&location_file), None);
+
+        let to = migrate_config(&from, &dir.path().join(".config").join("textra"), &location_file).unwrap();
+
+        assert_eq!(to, dir.path().join(".config").join("textra").join(CONFIG_FILE_NAME));
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "btw => by the way\n");
+        assert_eq!(recorded_config_path(&location_file), Some(to.clone()));
+
+        fs::write(&from, "omw => on my way\n").unwrap();
+        assert!(migrate_config(&from, &to, &location_file).is_err());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "btw => by the way\n");
+    }
+
+    #[test]
+    fn test_migrate_config_keeps_original_when_pointer_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join(CONFIG_FILE_NAME);
+        fs::write(&from, "btw => by the way\n").unwrap();
+        // A file where the install dir should be, so the pointer can't be written.
+        fs::write(dir.path().join(".textra"), "").unwrap();
+        let location_file = dir.path().join(".textra").join(LOCATION_FILE_NAME);
+        let target = dir.path().join("dotfiles");
+
+        assert!(migrate_config(&from, &target, &location_file).is_err());
+        assert_eq!(fs::read_to_string(&from).unwrap(), "btw => by the way\n");
+        assert!(!target.join(CONFIG_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = Some(PathBuf::from("C:/Users/jo"));
+        assert_eq!(expand_home("~/.config/textra", home.clone()), PathBuf::from("C:/Users/jo").join(".config/textra"));
+        assert_eq!(expand_home("~", home.clone()), PathBuf::from("C:/Users/jo"));
+        assert_eq!(expand_home("~jo/textra", home.clone()), PathBuf::from("~jo/textra"));
+        assert_eq!(expand_home("D:/dotfiles", home), PathBuf::from("D:/dotfiles"));
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::Local;
+use std::backtrace::Backtrace;
+use std::fs;
+use std::io::{self, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+const CRASH_DIR_NAME: &str = "logs";
+
+/// `logs` in the install directory, where crash reports go.
+pub fn crash_log_dir() -> Option<PathBuf> {
+    crate::installer::install_dir_path().ok().map(|dir| dir.join(CRASH_DIR_NAME))
+}
+
+/// Writes a crash report to `crash_log_dir` whenever a thread panics. The
+/// daemon has no console, so without this a panic leaves no trace. Nothing
+/// is sent anywhere.
+pub fn install_panic_hook() {
+    if let Some(dir) = crash_log_dir() {
+        install_panic_hook_in(dir);
+    }
+}
+
+fn install_panic_hook_in(dir: PathBuf) {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => info.payload().downcast_ref::<String>().cloned().unwrap_or_default(),
+        };
+        let location = info
+            .location()
+            .map(|location| format!("{}:{}:{}", location.file(), location.line(), location.column()))
+            .unwrap_or_default();
+        // A failure to write the report is ignored: panicking in the hook
+        // would abort the process.
+        let _ = write_crash_report(&dir, &message, &location);
+        previous(info);
+    }));
+}
+
+fn write_crash_report(dir: &Path, message: &str, location: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let now = Local::now();
+    let report = format!(
+        "time: {}\nversion: {}\nthread: {}\nmessage: {}\nlocation: {}\n\n{}\n",
+        now.to_rfc3339(),
+        env!("CARGO_PKG_VERSION"),
+        thread::current().name().unwrap_or("unnamed"),
+        message,
+        location,
+        Backtrace::force_capture()
+    );
+    // Two threads can panic within the same millisecond, so a name that's
+    // taken gets a number instead of being overwritten.
+    let stem = format!("crash-{}", now.format("%Y%m%d-%H%M%S-%3f"));
+    let mut attempt = 1;
+    loop {
+        let name = match attempt {
+            1 => format!("{}.log", stem),
+            n => format!("{}-{}.log", stem, n),
+        };
+        let path = dir.join(name);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(report.as_bytes())?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process::Command;
+
+    const CHILD_DIR_VAR: &str = "TEXTRA_CRASH_TEST_DIR";
+
+    #[test]
+    fn test_panic_hook_writes_crash_log() {
+        // Runs again in a child process, which installs the hook and panics.
+        if let Ok(dir) = env::var(CHILD_DIR_VAR) {
+            install_panic_hook_in(PathBuf::from(dir));
+            panic!("induced crash");
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "crash::tests::test_panic_hook_writes_crash_log", "--test-threads=1"])
+            .env(CHILD_DIR_VAR, dir.path())
+            .status()
+            .unwrap();
+        assert!(!status.success());
+
+        let reports: Vec<PathBuf> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(reports.len(), 1);
+        let report = fs::read_to_string(&reports[0]).unwrap();
+        assert!(report.contains("message: induced crash"));
+        assert!(report.contains("crash.rs"));
+    }
+
+    #[test]
+    fn test_crash_reports_are_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_crash_report(dir.path(), "first", "a.rs:1:1").unwrap();
+        let second = write_crash_report(dir.path(), "second", "a.rs:2:1").unwrap();
+
+        assert_ne!(first, second);
+        assert!(fs::read_to_string(&first).unwrap().contains("message: first"));
+        assert!(fs::read_to_string(&second).unwrap().contains("message: second"));
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const LOG_FILE_NAME: &str = "expansions.jsonl";
+
+/// One line of the expansion log. The typed text is only kept when
+/// `expansion_log_text` is on, since snippets often hold personal details.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExpansionLogEntry {
+    pub timestamp: String,
+    pub trigger: String,
+    pub app: String,
+    pub length: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl ExpansionLogEntry {
+    pub fn new(now: DateTime<Local>, trigger: &str, app: &str, text: &str, keep_text: bool) -> Self {
+        Self {
+            timestamp: now.to_rfc3339_opts(SecondsFormat::Secs, false),
+            trigger: trigger.to_string(),
+            app: app.to_string(),
+            length: text.chars().count(),
+            text: keep_text.then(|| text.to_string()),
+        }
+    }
+}
+
+/// `expansions.jsonl` in the install directory.
+pub fn log_path() -> Option<PathBuf> {
+    crate::installer::install_dir_path().ok().map(|dir| dir.join(LOG_FILE_NAME))
+}
+
+pub fn append(path: &Path, entry: &ExpansionLogEntry) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let line = serde_json::to_string(entry)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{line}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_logged_entry_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE_NAME);
+        let now = Local.with_ymd_and_hms(2024, 1, 2, 15, 4, 5).unwrap();
+
+        append(&path, &ExpansionLogEntry::new(now, "btw", "notepad.exe", "by the way", false)).unwrap();
+        append(&path, &ExpansionLogEntry::new(now, "btw", "notepad.exe", "by the way", true)).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> =
+            contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+
+        let entry = lines[0].as_object().unwrap();
+        assert!(entry["timestamp"].as_str().unwrap().starts_with("2024-01-02T15:04:05"));
+        assert_eq!(entry["trigger"], "btw");
+        assert_eq!(entry["app"], "notepad.exe");
+        assert_eq!(entry["length"], 10);
+        assert!(!entry.contains_key("text"));
+
+        assert_eq!(lines[1]["text"], "by the way");
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use io::Write;
 use minimo::showln;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs;
 use std::fs::File;
@@ -61,27 +61,93 @@ pub fn is_installed() -> bool {
 pub fn handle_install() -> Result<()> {
     showln!(gray_dim, "trying to install textra...");
 
-    if is_service_running() {
-        showln!(
-            orange_bold,
-            "an instance of textra is already running, stopping it..."
-        );  
-        handle_stop().context("Failed to stop running instance")?;
+    for step in planned_install_steps()? {
+        step.run()?;
+    }
+
+    Ok(())
+}
+
+/// Prints what `textra install` would do without changing anything.
+pub fn handle_install_dry_run() -> Result<()> {
+    showln!(gray_dim, "dry run: ", yellow_bold, "nothing will be changed.");
+    for step in planned_install_steps()? {
+        showln!(gray_dim, "would ", white_bold, step.describe());
+    }
+    Ok(())
+}
+
+/// One thing `textra install` does, kept separate so a dry run can describe
+/// it without doing it.
+#[derive(Debug, Clone, PartialEq)]
+enum InstallStep {
+    StopRunningInstance,
+    CopyExecutable { from: PathBuf, to: PathBuf },
+    RecordHashes(PathBuf),
+    AddToPath(PathBuf),
+    SetAutostart(PathBuf),
+    CreateUninstaller(PathBuf),
+    StartService,
+}
+
+impl InstallStep {
+    fn describe(&self) -> String {
+        match self {
+            InstallStep::StopRunningInstance => "stop the running textra instance".to_string(),
+            InstallStep::CopyExecutable { from, to } => format!("copy {} to {}", from.display(), to.display()),
+            InstallStep::RecordHashes(dir) => format!("record the binaries' hashes in {}", dir.join(integrity::MANIFEST_FILE_NAME).display()),
+            InstallStep::AddToPath(dir) => format!("add {} to the user PATH", dir.display()),
+            InstallStep::SetAutostart(exe) => {
+                format!("set HKCU\\{}\\Textra to start {} at login", AUTO_START_PATH, exe.display())
+            }
+            InstallStep::CreateUninstaller(dir) => format!("create {}", dir.join("uninstall.bat").display()),
+            InstallStep::StartService => "start the textra service".to_string(),
+        }
+    }
+
+    fn run(&self) -> Result<()> {
+        match self {
+            InstallStep::StopRunningInstance => {
+                showln!(orange_bold, "an instance of textra is already running, stopping it...");
+                handle_stop().context("Failed to stop running instance")
+            }
+            InstallStep::CopyExecutable { from, to } => {
+                let install_dir = to.parent().context("Install path has no directory")?;
+                fs::create_dir_all(install_dir).context("Failed to create installation directory")?;
+                showln!(gray_dim, "copying ", yellow_bold, "textra.exe", gray_dim, " to ", yellow_bold, install_dir.to_string_lossy());
+                fs::copy(from, to).context("Failed to copy executable to install directory")?;
+                Ok(())
+            }
+            InstallStep::RecordHashes(dir) => {
+                integrity::record_binaries(dir).context("Failed to record the binaries' hashes")?;
+                Ok(())
+            }
+            InstallStep::AddToPath(dir) => add_to_path(dir).context("Failed to add Textra to PATH"),
+            InstallStep::SetAutostart(exe) => set_autostart(exe).context("Failed to set autostart"),
+            InstallStep::CreateUninstaller(dir) => create_uninstaller(dir).context("Failed to create uninstaller"),
+            InstallStep::StartService => handle_run().context("Failed to start service"),
+        }
     }
+}
 
+fn planned_install_steps() -> Result<Vec<InstallStep>> {
     let exe_path = env::current_exe().context("Failed to get current executable path")?;
-    let install_dir = get_install_dir()?;
+    Ok(install_steps(exe_path, &install_dir_path()?, is_service_running()))
+}
 
+fn install_steps(exe_path: PathBuf, install_dir: &std::path::Path, service_running: bool) -> Vec<InstallStep> {
     let install_path = install_dir.join("textra.exe");
-    showln!(gray_dim, "copying ", yellow_bold, "textra.exe", gray_dim, " to ", yellow_bold, install_dir.to_string_lossy());
-    fs::copy(&exe_path, &install_path).context("Failed to copy executable to install directory")?;
-
-    add_to_path(&install_dir).context("Failed to add Textra to PATH")?;
-    set_autostart(&install_path).context("Failed to set autostart")?;
-    create_uninstaller(&install_dir).context("Failed to create uninstaller")?;
-    handle_run().context("Failed to start service")?;
- 
-    Ok(())
+    let mut steps = Vec::new();
+    if service_running {
+        steps.push(InstallStep::StopRunningInstance);
+    }
+    steps.push(InstallStep::CopyExecutable { from: exe_path, to: install_path.clone() });
+    steps.push(InstallStep::RecordHashes(install_dir.to_path_buf()));
+    steps.push(InstallStep::AddToPath(install_dir.to_path_buf()));
+    steps.push(InstallStep::SetAutostart(install_path));
+    steps.push(InstallStep::CreateUninstaller(install_dir.to_path_buf()));
+    steps.push(InstallStep::StartService);
+    steps
 }
 
 pub fn is_running_from_install_dir() -> bool {
@@ -139,13 +205,17 @@ pub fn handle_uninstall() -> Result<()> {
 }
 
 fn get_install_dir() -> Result<PathBuf> {
-    let d = dirs::home_dir()
-        .map(|dir| dir.join(".textra"))
-        .context("Failed to determine local data directory")?;
+    let d = install_dir_path()?;
     fs::create_dir_all(&d).context("Failed to create installation directory")?;
     Ok(d)
 }
 
+pub(crate) fn install_dir_path() -> Result<PathBuf> {
+    dirs::home_dir()
+        .map(|dir| dir.join(".textra"))
+        .context("Failed to determine local data directory")
+}
+
 fn add_to_path(install_dir: &std::path::Path) -> Result<()> {
     let hkcu = RegKey::predef(HKEY_CURRENT_USER);
     let (env, _) = hkcu
@@ -378,23 +448,24 @@ impl Ord for Version {
 const CREATE_NO_WINDOW: u32 = 0x08000000;
 const DETACHED_PROCESS: u32 = 0x00000008;
 pub fn handle_update() -> Result<()> {
-    let latest_release = get_latest_release()?;
+    update_from(&GitHubReleaseProvider)
+}
+
+fn update_from(provider: &impl ReleaseProvider) -> Result<()> {
+    let latest_release = provider.latest_release()?;
     let latest_version = parse_version_from_tag(&latest_release.tag_name)?;
-    println!("assets: {:?}", latest_release.assets);
-    let textra_asset = latest_release.assets
-        .iter()
-        .find(|asset| asset.name == "textra.exe")
-        .context("Could not find textra.exe in release assets")?;
+    let textra_asset = release_asset(&latest_release)?;
 
     // Get paths
     let install_dir = get_install_dir()?;
     let current_exe = env::current_exe()?;
     let new_exe_path = install_dir.join("textra.new.exe");
+    let previous_exe_path = install_dir.join(PREVIOUS_EXE_NAME);
     let update_script_path = install_dir.join("update.bat");
 
     // Download new version first
     showln!(gray_dim, "downloading version ", yellow_bold, &latest_version.to_string());
-    download_file(&textra_asset.browser_download_url, &new_exe_path)?;
+    provider.download(&textra_asset.browser_download_url, &new_exe_path)?;
 
     // Create update batch script
     let batch_script = format!(
@@ -408,6 +479,9 @@ rem Kill any running instances of textra
 taskkill /F /IM textra.exe /T >nul 2>&1
 timeout /t 1 /nobreak >nul
 
+rem Keep the old version for textra rollback
+copy /Y "{current}" "{previous}" >nul 2>&1
+
 :RETRY_COPY
 rem Try to copy new version over old version
 copy /Y "{new}" "{current}" >nul 2>&1
@@ -424,10 +498,18 @@ del "{new}" >nul 2>&1
 del "%~f0"
 "#,
         new = new_exe_path.display(),
-        current = current_exe.display()
+        current = current_exe.display(),
+        previous = previous_exe_path.display()
     );
 
     fs::write(&update_script_path, batch_script)?;
+    fs::write(install_dir.join(UPDATE_MARKER_NAME), "0")?;
+    // Record what the files will be once the script has run.
+    let manifest = integrity::Manifest::from([
+        ("textra.exe".to_string(), integrity::file_hash(&new_exe_path)?),
+        (PREVIOUS_EXE_NAME.to_string(), integrity::file_hash(&current_exe)?),
+    ]);
+    integrity::save_manifest(&install_dir, &manifest)?;
 
     // Launch the update script and exit
     showln!(gray_dim, "starting update process...");
@@ -445,6 +527,119 @@ del "%~f0"
     std::process::exit(0);
 }
 
+const PREVIOUS_EXE_NAME: &str = "textra.prev.exe";
+/// Written by an update and removed once the new version has run for
+/// `STABLE_AFTER_UPDATE_SECS`. It counts the launches in between.
+const UPDATE_MARKER_NAME: &str = "update_marker";
+const STABLE_AFTER_UPDATE_SECS: u64 = 60;
+/// Launches without settling down after an update before rolling back.
+const MAX_LAUNCHES_AFTER_UPDATE: u32 = 3;
+
+/// Puts back the version that was installed before the last update, keeping
+/// the newer one in its place so the rollback can be undone the same way.
+pub fn handle_rollback() -> Result<()> {
+    let install_dir = get_install_dir()?;
+    let current = install_dir.join("textra.exe");
+    let previous = install_dir.join(PREVIOUS_EXE_NAME);
+    if !previous.exists() {
+        showln!(orange_bold, "there is no previous version to roll back to.");
+        return Ok(());
+    }
+    if is_service_running() {
+        handle_stop()?;
+    }
+    swap_binaries(&current, &previous)?;
+    if let Err(e) = integrity::swap_entries(&install_dir, "textra.exe", PREVIOUS_EXE_NAME) {
+        eprintln!("Failed to update {}: {}", integrity::MANIFEST_FILE_NAME, e);
+    }
+    let _ = fs::remove_file(install_dir.join(UPDATE_MARKER_NAME));
+    Command::new(&current)
+        .arg("run")
+        .spawn()
+        .context("Failed to restart Textra")?;
+    showln!(gray_dim, "rolled back to the ", green_bold, "previous version", gray_dim, ".");
+    Ok(())
+}
+
+/// Checks the installed binaries against the hashes recorded when they were
+/// installed.
+pub fn handle_verify() -> Result<()> {
+    let install_dir = install_dir_path()?;
+    let manifest = match integrity::load_manifest(&install_dir) {
+        Ok(manifest) => manifest,
+        Err(_) => {
+            showln!(orange_bold, "no hashes were recorded for this install. run ", yellow_bold, "textra install", orange_bold, " to record them.");
+            return Ok(());
+        }
+    };
+    let mut damaged = false;
+    for (name, status) in integrity::verify(&install_dir, &manifest) {
+        match status {
+            integrity::FileStatus::Intact => showln!(gray_dim, name, ": ", green_bold, "ok"),
+            integrity::FileStatus::Modified => showln!(gray_dim, name, ": ", red_bold, "modified"),
+            integrity::FileStatus::Missing => showln!(gray_dim, name, ": ", red_bold, "missing"),
+        }
+        damaged |= status != integrity::FileStatus::Intact;
+    }
+    if damaged {
+        showln!(orange_bold, "some files don't match. reinstall textra with ", yellow_bold, "textra install", orange_bold, " from a fresh download.");
+    }
+    Ok(())
+}
+
+/// Swaps two files by renaming, which Windows allows even while one of them
+/// is running.
+fn swap_binaries(current: &std::path::Path, previous: &std::path::Path) -> Result<()> {
+    let parked = current.with_extension("swap.exe");
+    fs::rename(current, &parked).context("Failed to move the current version aside")?;
+    if let Err(e) = fs::rename(previous, current) {
+        fs::rename(&parked, current)?;
+        return Err(e).context("Failed to restore the previous version");
+    }
+    fs::rename(&parked, previous).context("Failed to keep the newer version")?;
+    Ok(())
+}
+
+/// Counts a daemon launch after an update. Returns true when the new version
+/// has been launched too often without settling down and should be rolled
+/// back.
+fn record_launch_after_update(marker: &std::path::Path) -> bool {
+    let launches = match fs::read_to_string(marker) {
+        Ok(text) => text.trim().parse::<u32>().unwrap_or(0) + 1,
+        Err(_) => return false,
+    };
+    if let Err(e) = fs::write(marker, launches.to_string()) {
+        eprintln!("Failed to update {}: {}", marker.display(), e);
+    }
+    launches > MAX_LAUNCHES_AFTER_UPDATE
+}
+
+/// Called as the daemon starts. Rolls back and restarts the previous version
+/// if the current one keeps failing right after an update; otherwise clears
+/// the update marker once the daemon has run for a while.
+pub fn check_update_health() -> Result<()> {
+    let install_dir = install_dir_path()?;
+    let marker = install_dir.join(UPDATE_MARKER_NAME);
+    if record_launch_after_update(&marker) {
+        eprintln!("Textra keeps stopping since the last update, rolling back to the previous version");
+        if install_dir.join(PREVIOUS_EXE_NAME).exists() {
+            let current = install_dir.join("textra.exe");
+            swap_binaries(&current, &install_dir.join(PREVIOUS_EXE_NAME))?;
+            let _ = integrity::swap_entries(&install_dir, "textra.exe", PREVIOUS_EXE_NAME);
+            let _ = fs::remove_file(&marker);
+            Command::new(&current).arg("daemon").creation_flags(DETACHED_PROCESS).spawn()?;
+            std::process::exit(0);
+        }
+        let _ = fs::remove_file(&marker);
+    } else if marker.exists() {
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(STABLE_AFTER_UPDATE_SECS));
+            let _ = fs::remove_file(&marker);
+        });
+    }
+    Ok(())
+}
+
 fn download_file(url: &str, path: &PathBuf) -> Result<()> {
     let client = reqwest::blocking::Client::new();
     let response = client
@@ -466,26 +661,154 @@ fn download_file(url: &str, path: &PathBuf) -> Result<()> {
     }
 }
 
-fn get_latest_release() -> Result<GitHubRelease> {
+/// Why the latest release couldn't be looked up. Being offline is expected
+/// and skipped quietly; anything else is worth a warning.
+#[derive(Debug)]
+pub enum ReleaseError {
+    Offline(String),
+    Api(anyhow::Error),
+}
+
+impl std::fmt::Display for ReleaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReleaseError::Offline(reason) => write!(f, "No network connection: {}", reason),
+            ReleaseError::Api(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReleaseError {}
+
+/// Where releases come from, so the update logic can be tested without a
+/// network.
+trait ReleaseProvider {
+    fn latest_release(&self) -> Result<GitHubRelease, ReleaseError>;
+    fn download(&self, url: &str, path: &PathBuf) -> Result<()>;
+}
+
+struct GitHubReleaseProvider;
+
+impl ReleaseProvider for GitHubReleaseProvider {
+    fn latest_release(&self) -> Result<GitHubRelease, ReleaseError> {
+        get_latest_release()
+    }
+
+    fn download(&self, url: &str, path: &PathBuf) -> Result<()> {
+        download_file(url, path)
+    }
+}
+
+fn release_asset(release: &GitHubRelease) -> Result<&GitHubAsset> {
+    release.assets
+        .iter()
+        .find(|asset| asset.name == "textra.exe")
+        .context("Could not find textra.exe in release assets")
+}
+
+fn get_latest_release() -> Result<GitHubRelease, ReleaseError> {
     let client = reqwest::blocking::Client::builder()
         .timeout(Duration::from_secs(10))
-        .build()?;
+        .build()
+        .map_err(|e| ReleaseError::Api(e.into()))?;
 
     let response = client
         .get("https://api.github.com/repos/u-tra/textra/releases/latest")
         .header("User-Agent", "Textra-Updater")
         .send()
-        .context("Failed to contact GitHub API")?;
+        .map_err(|e| {
+            if e.is_connect() || e.is_timeout() {
+                ReleaseError::Offline(e.to_string())
+            } else {
+                ReleaseError::Api(anyhow::Error::new(e).context("Failed to contact GitHub API"))
+            }
+        })?;
 
     if response.status().is_success() {
         response.json::<GitHubRelease>()
-            .context("Failed to parse GitHub response")
+            .map_err(|e| ReleaseError::Api(anyhow::Error::new(e).context("Failed to parse GitHub response")))
     } else {
-        Err(anyhow::anyhow!("GitHub API returned status: {}", response.status()))
+        Err(ReleaseError::Api(anyhow::anyhow!("GitHub API returned status: {}", response.status())))
     }
 }
 
- 
+/// How long a lookup of the latest release is trusted before GitHub is asked
+/// again.
+const UPDATE_CHECK_INTERVAL_HOURS: i64 = 24;
+const UPDATE_CACHE_FILE_NAME: &str = "update_check.json";
+
+/// The last lookup of the latest release, kept in the install directory so
+/// repeated launches don't ask GitHub every time.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct UpdateCache {
+    /// Unix time of the last attempt, including ones made while offline.
+    checked_at: Option<i64>,
+    latest: Option<String>,
+}
+
+impl UpdateCache {
+    fn load(path: &std::path::Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &std::path::Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        crate::config::write_config_atomically(path, &serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateCheck {
+    Available(Version),
+    UpToDate,
+    Offline,
+    Disabled,
+}
+
+/// Update checks are skipped entirely while `TEXTRA_OFFLINE` is set.
+fn updates_disabled() -> bool {
+    env::var_os("TEXTRA_OFFLINE").map_or(false, |value| !value.is_empty() && value != "0")
+}
+
+/// Compares `current` with the latest release, asking `provider` only when
+/// the cached answer is older than `max_age`.
+fn check_latest_version(
+    current: &Version,
+    cache: &mut UpdateCache,
+    now: i64,
+    max_age: chrono::Duration,
+    provider: &impl ReleaseProvider,
+) -> Result<UpdateCheck> {
+    let fresh = cache.checked_at.map_or(false, |checked_at| now - checked_at < max_age.num_seconds());
+    let tag = if fresh {
+        match &cache.latest {
+            Some(tag) => tag.clone(),
+            None => return Ok(UpdateCheck::Offline),
+        }
+    } else {
+        cache.checked_at = Some(now);
+        match provider.latest_release() {
+            Ok(release) => {
+                cache.latest = Some(release.tag_name.clone());
+                release.tag_name
+            }
+            Err(ReleaseError::Offline(_)) => {
+                // Don't trust an older answer either; try again after max_age.
+                cache.latest = None;
+                return Ok(UpdateCheck::Offline);
+            }
+            Err(ReleaseError::Api(e)) => return Err(e),
+        }
+    };
+    let latest = parse_version_from_tag(&tag)?;
+    Ok(if latest > *current { UpdateCheck::Available(latest) } else { UpdateCheck::UpToDate })
+}
 
 fn get_current_version() -> Result<Version> {
     let version_str = env!("CARGO_PKG_VERSION");
@@ -502,36 +825,217 @@ pub fn update_if_available() -> Result<()> {
     let current_version = get_current_version()?;
     showln!(gray_dim, "checking for updates (current version: ", yellow_bold, &current_version.to_string(), gray_dim, ")");
 
-    match check_for_updates() {
-        Ok(true) => {
-            showln!(gray_dim, "new version available, preparing update...");
+    // Asked for explicitly, so don't go by an earlier answer.
+    match check_for_updates_within(chrono::Duration::zero()) {
+        Ok(UpdateCheck::Available(version)) => {
+            showln!(gray_dim, "version ", yellow_bold, &version.to_string(), gray_dim, " available, preparing update...");
             handle_update()
         }
-        Ok(false) => {
+        Ok(UpdateCheck::UpToDate) => {
             showln!(gray_dim, "textra is up to date!");
             Ok(())
         }
+        Ok(UpdateCheck::Offline) => {
+            showln!(gray_dim, "no network connection, skipped the update check.");
+            Ok(())
+        }
+        Ok(UpdateCheck::Disabled) => {
+            showln!(gray_dim, "update checks are turned off (", yellow_bold, "TEXTRA_OFFLINE", gray_dim, " is set).");
+            Ok(())
+        }
         Err(e) => {
-            showln!(orange_bold, "failed to check for updates: {}", e);
+            showln!(orange_bold, "failed to check for updates: ", white_bold, e.to_string());
             Err(e)
         }
     }
 }
 
-pub fn check_for_updates() -> Result<bool> {
+/// Checks for a newer release, asking GitHub at most once a day.
+pub fn check_for_updates() -> Result<UpdateCheck> {
+    check_for_updates_within(chrono::Duration::hours(UPDATE_CHECK_INTERVAL_HOURS))
+}
+
+fn check_for_updates_within(max_age: chrono::Duration) -> Result<UpdateCheck> {
+    if updates_disabled() {
+        return Ok(UpdateCheck::Disabled);
+    }
     let current_version = get_current_version()?;
-    showln!(gray_dim, "current version: ", yellow_bold, &current_version.to_string());
-    
-    match get_latest_release() {
-        Ok(latest_release) => {
-            let latest_version = parse_version_from_tag(&latest_release.tag_name)?;
-            showln!(gray_dim, "latest version: ", yellow_bold, &latest_version.to_string());
-            
-            Ok(latest_version > current_version)
+    let cache_path = install_dir_path()?.join(UPDATE_CACHE_FILE_NAME);
+    let mut cache = UpdateCache::load(&cache_path);
+    let now = chrono::Utc::now().timestamp();
+    let result = check_latest_version(&current_version, &mut cache, now, max_age, &GitHubReleaseProvider);
+    if let Err(e) = cache.save(&cache_path) {
+        eprintln!("Failed to save update check: {}", e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_dry_run_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe_path = dir.path().join("downloads").join("textra.exe");
+        let install_dir = dir.path().join(".textra");
+
+        let steps = install_steps(exe_path.clone(), &install_dir, false);
+        let descriptions: Vec<String> = steps.iter().map(InstallStep::describe).collect();
+
+        assert_eq!(
+            steps.first(),
+            Some(&InstallStep::CopyExecutable { from: exe_path, to: install_dir.join("textra.exe") })
+        );
+        assert_eq!(steps.last(), Some(&InstallStep::StartService));
+        assert!(descriptions.iter().any(|line| line.contains("uninstall.bat")));
+        assert!(!install_dir.exists());
+    }
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    /// Serves a fixed answer and counts how often it was asked.
+    struct FakeReleaseProvider {
+        tag: Option<&'static str>,
+        calls: std::cell::Cell<usize>,
+    }
+
+    impl FakeReleaseProvider {
+        fn new(tag: Option<&'static str>) -> Self {
+            Self { tag, calls: std::cell::Cell::new(0) }
         }
-        Err(e) => {
-            showln!(orange_bold, "failed to check for updates: {}", e);
-            Ok(false)
+    }
+
+    impl ReleaseProvider for FakeReleaseProvider {
+        fn latest_release(&self) -> Result<GitHubRelease, ReleaseError> {
+            self.calls.set(self.calls.get() + 1);
+            match self.tag {
+                Some(tag) => Ok(GitHubRelease {
+                    tag_name: tag.to_string(),
+                    assets: vec![GitHubAsset {
+                        name: "textra.exe".to_string(),
+                        browser_download_url: format!("https://example.com/{}/textra.exe", tag),
+                    }],
+                }),
+                None => Err(ReleaseError::Offline("no route to host".to_string())),
+            }
+        }
+
+        fn download(&self, _url: &str, _path: &PathBuf) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn check(provider: &FakeReleaseProvider, cache: &mut UpdateCache, now: i64) -> Result<UpdateCheck> {
+        check_latest_version(&version("0.1.72"), cache, now, chrono::Duration::hours(24), provider)
+    }
+
+    #[test]
+    fn test_update_available_from_higher_release() {
+        let provider = FakeReleaseProvider::new(Some("v0.2.0"));
+        assert_eq!(check(&provider, &mut UpdateCache::default(), 1_000).unwrap(), UpdateCheck::Available(version("0.2.0")));
+
+        let provider = FakeReleaseProvider::new(Some("v0.1.72"));
+        assert_eq!(check(&provider, &mut UpdateCache::default(), 1_000).unwrap(), UpdateCheck::UpToDate);
+        let provider = FakeReleaseProvider::new(Some("v0.1.9"));
+        assert_eq!(check(&provider, &mut UpdateCache::default(), 1_000).unwrap(), UpdateCheck::UpToDate);
+    }
+
+    #[test]
+    fn test_release_asset_picks_the_executable() {
+        let release = FakeReleaseProvider::new(Some("v0.2.0")).latest_release().unwrap();
+        assert_eq!(release_asset(&release).unwrap().browser_download_url, "https://example.com/v0.2.0/textra.exe");
+        let empty = GitHubRelease { tag_name: "v0.2.0".to_string(), assets: Vec::new() };
+        assert!(release_asset(&empty).is_err());
+    }
+
+    #[test]
+    fn test_update_check_offline_is_quiet_and_remembered() {
+        let provider = FakeReleaseProvider::new(None);
+        let mut cache = UpdateCache::default();
+
+        assert_eq!(check(&provider, &mut cache, 1_000).unwrap(), UpdateCheck::Offline);
+        assert_eq!(cache.checked_at, Some(1_000));
+
+        // Within the interval GitHub isn't asked again.
+        assert_eq!(check(&provider, &mut cache, 2_000).unwrap(), UpdateCheck::Offline);
+        assert_eq!(provider.calls.get(), 1);
+    }
+
+    #[test]
+    fn test_update_check_uses_recent_cache() {
+        let provider = FakeReleaseProvider::new(Some("v0.1.72"));
+        let mut cache = UpdateCache { checked_at: Some(1_000), latest: Some("v0.2.0".to_string()) };
+        assert_eq!(check(&provider, &mut cache, 1_060).unwrap(), UpdateCheck::Available(version("0.2.0")));
+        assert_eq!(provider.calls.get(), 0);
+
+        let stale = 1_000 + 25 * 3600;
+        assert_eq!(check(&provider, &mut cache, stale).unwrap(), UpdateCheck::UpToDate);
+        assert_eq!(cache, UpdateCache { checked_at: Some(stale), latest: Some("v0.1.72".to_string()) });
+    }
+
+    #[test]
+    fn test_update_check_reports_api_errors() {
+        struct RateLimited;
+        impl ReleaseProvider for RateLimited {
+            fn latest_release(&self) -> Result<GitHubRelease, ReleaseError> {
+                Err(ReleaseError::Api(anyhow::anyhow!("GitHub API returned status: 403")))
+            }
+
+            fn download(&self, _url: &str, _path: &PathBuf) -> Result<()> {
+                Ok(())
+            }
         }
+
+        let mut cache = UpdateCache::default();
+        let check = check_latest_version(&version("0.1.72"), &mut cache, 1_000, chrono::Duration::hours(24), &RateLimited);
+        assert!(check.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rollback_swaps_binaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = dir.path().join("textra.exe");
+        let previous = dir.path().join(PREVIOUS_EXE_NAME);
+        fs::write(&current, "new").unwrap();
+        fs::write(&previous, "old").unwrap();
+
+        swap_binaries(&current, &previous).unwrap();
+        assert_eq!(fs::read_to_string(&current).unwrap(), "old");
+        assert_eq!(fs::read_to_string(&previous).unwrap(), "new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // Rolling back again undoes it.
+        swap_binaries(&current, &previous).unwrap();
+        assert_eq!(fs::read_to_string(&current).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_rollback_without_previous_keeps_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = dir.path().join("textra.exe");
+        fs::write(&current, "new").unwrap();
+
+        assert!(swap_binaries(&current, &dir.path().join(PREVIOUS_EXE_NAME)).is_err());
+        assert_eq!(fs::read_to_string(&current).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_repeated_launches_after_update_roll_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join(UPDATE_MARKER_NAME);
+        assert!(!record_launch_after_update(&marker));
+
+        fs::write(&marker, "0").unwrap();
+        let launches: Vec<bool> = (0..4).map(|_| record_launch_after_update(&marker)).collect();
+        assert_eq!(launches, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn test_install_stops_running_instance_first() {
+        let steps = install_steps(PathBuf::from("textra.exe"), &PathBuf::from(".textra"), true);
+        assert_eq!(steps[0], InstallStep::StopRunningInstance);
+    }
+}
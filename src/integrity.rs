@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// SHA-256 hashes of the installed binaries by file name, written when they
+/// are installed or updated.
+pub type Manifest = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Intact,
+    Modified,
+    Missing,
+}
+
+pub fn file_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub fn load_manifest(dir: &Path) -> io::Result<Manifest> {
+    let text = fs::read_to_string(dir.join(MANIFEST_FILE_NAME))?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+pub fn save_manifest(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    let text = serde_json::to_string_pretty(manifest)?;
+    crate::config::write_config_atomically(&dir.join(MANIFEST_FILE_NAME), &text)
+}
+
+/// Records the hash of every `.exe` in `dir`, replacing the old manifest.
+pub fn record_binaries(dir: &Path) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("exe")) {
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                manifest.insert(name.to_string(), file_hash(&path)?);
+            }
+        }
+    }
+    save_manifest(dir, &manifest)?;
+    Ok(manifest)
+}
+
+/// Swaps the recorded hashes of two files after the files themselves were
+/// swapped.
+pub fn swap_entries(dir: &Path, a: &str, b: &str) -> io::Result<()> {
+    let mut manifest = load_manifest(dir)?;
+    let (hash_a, hash_b) = (manifest.remove(a), manifest.remove(b));
+    manifest.extend(hash_b.map(|hash| (a.to_string(), hash)));
+    manifest.extend(hash_a.map(|hash| (b.to_string(), hash)));
+    save_manifest(dir, &manifest)
+}
+
+/// Compares the files in `dir` with the hashes in `manifest`.
+pub fn verify(dir: &Path, manifest: &Manifest) -> Vec<(String, FileStatus)> {
+    manifest
+        .iter()
+        .map(|(name, expected)| {
+            let status = match file_hash(&dir.join(name)) {
+                Ok(hash) if hash.eq_ignore_ascii_case(expected) => FileStatus::Intact,
+                Ok(_) => FileStatus::Modified,
+                Err(_) => FileStatus::Missing,
+            };
+            (name.clone(), status)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_file_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("textra.exe"), "hello").unwrap();
+        assert_eq!(file_hash(&dir.path().join("textra.exe")).unwrap(), HELLO_SHA256);
+    }
+
+    #[test]
+    fn test_verify_against_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("textra.exe"), "hello").unwrap();
+        fs::write(dir.path().join("textra.prev.exe"), "hello, corrupted").unwrap();
+        let manifest = Manifest::from([
+            ("textra.exe".to_string(), HELLO_SHA256.to_uppercase()),
+            ("textra.prev.exe".to_string(), HELLO_SHA256.to_string()),
+            ("helper.exe".to_string(), HELLO_SHA256.to_string()),
+        ]);
+
+        assert_eq!(
+            verify(dir.path(), &manifest),
+            vec![
+                ("helper.exe".to_string(), FileStatus::Missing),
+                ("textra.exe".to_string(), FileStatus::Intact),
+                ("textra.prev.exe".to_string(), FileStatus::Modified),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_binaries_skips_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("textra.exe"), "hello").unwrap();
+        fs::write(dir.path().join("config.textra"), "btw => by the way").unwrap();
+
+        record_binaries(dir.path()).unwrap();
+        let manifest = load_manifest(dir.path()).unwrap();
+        assert_eq!(manifest, Manifest::from([("textra.exe".to_string(), HELLO_SHA256.to_string())]));
+
+        fs::write(dir.path().join("textra.prev.exe"), "old").unwrap();
+        record_binaries(dir.path()).unwrap();
+        let old_hash = file_hash(&dir.path().join("textra.prev.exe")).unwrap();
+        swap_entries(dir.path(), "textra.exe", "textra.prev.exe").unwrap();
+        let manifest = load_manifest(dir.path()).unwrap();
+        assert_eq!(manifest["textra.exe"], old_hash);
+        assert_eq!(manifest["textra.prev.exe"], HELLO_SHA256);
+    }
+}
@@ -1,10 +1,10 @@
-use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+use std::sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::collections::{HashMap, VecDeque};
 use std::thread;
-use chrono::Local;
+use chrono::{Local, NaiveDate};
 use winapi::um::{libloaderapi::GetModuleHandleW, winuser::*, wingdi::*};
-use winapi::shared::{minwindef::*, windef::*};
+use winapi::shared::{basetsd::DWORD_PTR, minwindef::*, windef::*};
 use winapi::ctypes::c_int;
 use std::{ptr, mem};
 use std::process::Command;
@@ -14,16 +14,34 @@ use notify::{Watcher, RecursiveMode};
 use std::path::Path;
 use anyhow::Result;
 use lazy_static::lazy_static;
+use regex::Regex;
 use tempfile::Builder;
 
-use crate::{load_config, view, watch_config, AppState, Replacement, TextraConfig, MAX_TEXT_LENGTH};
+use crate::{clipboard, expansion_log, load_config, InputMode, rotation, snooze, CodeFailure, normalize_line_endings, view, watch_config, AppState, Replacement, RuleOptions, TextraConfig, TextraRule, TextraSettings, MAX_TEXT_LENGTH, SECRET_MASK};
+use crate::expansion_log::ExpansionLogEntry;
+use crate::replacement::{process_dynamic_replacement, propagate_case_fn, substitute_params};
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::winbase::QueryFullProcessImageNameW;
+use winapi::um::processthreadsapi::{GetCurrentProcess, GetCurrentProcessId, OpenProcess, OpenProcessToken};
+use winapi::um::wincon::GetConsoleWindow;
+use winapi::um::securitybaseapi::{GetSidSubAuthority, GetSidSubAuthorityCount, GetTokenInformation};
+use winapi::um::winnt::{
+    TokenIntegrityLevel, HANDLE, PROCESS_QUERY_LIMITED_INFORMATION, SECURITY_MANDATORY_HIGH_RID,
+    TOKEN_MANDATORY_LABEL, TOKEN_QUERY,
+};
 
 const KEY_DELAY: u64 = 2;
+const PASTE_SETTLE_DELAY: u64 = 100;
+const SETTLE_POLL: Duration = Duration::from_millis(5);
+const MAX_KEY_REPEAT: usize = 100;
+const CODE_FAILURE_MARKER: &str = "[code failed]";
 
 #[derive(Debug, Clone, Copy)]
 pub enum Message {
     KeyEvent(DWORD, WPARAM, LPARAM),
     ConfigReload,
+    Resume,
+    Click,
     Quit,
 }
 
@@ -40,13 +58,26 @@ pub fn main_loop(app_state: Arc<AppState>, receiver: &std::sync::mpsc::Receiver<
                     eprintln!("Error reloading config: {}", e);
                 }
             }
+            Message::Resume => app_state.reset_input_state(),
+            Message::Click => {
+                app_state.note_click();
+            }
             Message::Quit => break,
         }
     }
     Ok(())
 }
 
+/// A piece of a replacement: literal text, or a key press written as a token
+/// such as `{{enter}}`.
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Key(i32),
+}
+
 lazy_static! {
+    static ref KEY_TOKEN: Regex = Regex::new(r"(?i)\{\{\s*(enter|tab|key:[^{}]*?)\s*\}\}").unwrap();
     static ref SYMBOL_PAIRS: HashMap<char, char> = {
         let mut m = HashMap::new();
         m.insert(';', ':');
@@ -81,19 +112,42 @@ fn handle_key_event(
     l_param: LPARAM,
 ) -> Result<()> {
     let now = Instant::now();
+    let (cancel_key, force_expand_key, buffer_timeout_ms) = {
+        let config = app_state.config.lock().unwrap();
+        (cancel_key_code(&config.settings), force_expand_key_code(&config.settings), config.settings.buffer_timeout_ms)
+    };
 
     match w_param as u32 {
         WM_KEYDOWN | WM_SYSKEYDOWN => {
+            KEY_DOWNS_HANDLED.fetch_add(1, Ordering::SeqCst);
+            app_state.metrics.record_keystroke();
+            // WM_INPUTLANGCHANGE only goes to the focused window, so a layout
+            // switch is noticed on the next key instead.
+            app_state.note_keyboard_layout(foreground_keyboard_layout() as usize);
+            app_state.note_focused_window(unsafe { GetForegroundWindow() } as usize);
             let mut last_key_time = app_state.last_key_time.lock().unwrap();
-            if now.duration_since(*last_key_time) > Duration::from_millis(1000) {
-                app_state.current_text.lock().unwrap().clear();
+            if buffer_timed_out(now.duration_since(*last_key_time), buffer_timeout_ms) {
+                let mut current_text = app_state.current_text.lock().unwrap();
+                // Keep a trailing line break so LineStart rules still know
+                // where the line began.
+                let ends_line = matches!(current_text.back(), Some('\r') | Some('\n'));
+                current_text.clear();
+                if ends_line {
+                    current_text.push_back('\n');
+                }
             }
             *last_key_time = now;
 
             match vk_code as i32 {
-                VK_ESCAPE => {
+                key if key == cancel_key => {
                     app_state.killswitch.store(true, Ordering::SeqCst);
                 }
+                key if Some(key) == force_expand_key => {
+                    let pending = force_expand(&app_state, &mut app_state.current_text.lock().unwrap())?;
+                    if let Some(pending) = pending {
+                        confirm_and_expand(&app_state, pending)?;
+                    }
+                }
                 VK_SHIFT | VK_LSHIFT | VK_RSHIFT => {
                     app_state.shift_pressed.store(true, Ordering::SeqCst);
                 }
@@ -120,17 +174,15 @@ fn handle_key_event(
                         app_state.shift_pressed.load(Ordering::SeqCst),
                         app_state.caps_lock_on.load(Ordering::SeqCst),
                     ) {
-                        let mut current_text = app_state.current_text.lock().unwrap();
-                        current_text.push_back(c);
-                        if current_text.len() > MAX_TEXT_LENGTH {
-                            current_text.pop_front();
-                        }
-                        check_and_replace(&app_state, &mut current_text)?;
+                        buffer_typed_char(&app_state, c, ime_composing())?;
                     }
                 }
             }
         }
         WM_KEYUP | WM_SYSKEYUP => match vk_code as i32 {
+            key if key == cancel_key => {
+                app_state.killswitch.store(false, Ordering::SeqCst);
+            }
             VK_SHIFT | VK_LSHIFT | VK_RSHIFT => {
                 app_state.shift_pressed.store(false, Ordering::SeqCst);
             }
@@ -140,9 +192,6 @@ fn handle_key_event(
             VK_MENU | VK_LMENU | VK_RMENU => {
                 app_state.alt_pressed.store(false, Ordering::SeqCst);
             }
-            VK_ESCAPE => {
-                app_state.killswitch.store(false, Ordering::SeqCst);
-            }
             _ => {}
         },
         _ => {}
@@ -151,6 +200,44 @@ fn handle_key_event(
     Ok(())
 }
 
+/// Whether a pause this long means the next key starts new text. A timeout
+/// of `0` never does; the buffer is still capped at `MAX_TEXT_LENGTH`.
+fn buffer_timed_out(idle: Duration, buffer_timeout_ms: u64) -> bool {
+    buffer_timeout_ms > 0 && idle > Duration::from_millis(buffer_timeout_ms)
+}
+
+/// Adds a typed character to the buffer and expands any trigger it completes.
+/// While an IME is composing, the keys pressed aren't the text that ends up
+/// in the document, so the buffer is dropped instead.
+fn buffer_typed_char(app_state: &AppState, c: char, ime_composing: bool) -> Result<()> {
+    let pending = {
+        let mut current_text = app_state.current_text.lock().unwrap();
+        if ime_composing {
+            current_text.clear();
+            return Ok(());
+        }
+        current_text.push_back(c);
+        if current_text.len() > MAX_TEXT_LENGTH {
+            current_text.pop_front();
+        }
+        let pending = check_and_replace(app_state, &mut current_text)?;
+
+        // A reset character ends whatever came before it, so a trigger can't
+        // match across it. It's checked after expanding so it can still commit one.
+        if app_state.config.lock().unwrap().settings.is_reset_char(c) {
+            current_text.clear();
+            if c == '\r' || c == '\n' {
+                current_text.push_back('\n');
+            }
+        }
+        pending
+    };
+    if let Some(pending) = pending {
+        confirm_and_expand(app_state, pending)?;
+    }
+    Ok(())
+}
+
 fn get_char_from_vk(vk_code: i32, shift_pressed: bool, caps_lock_on: bool) -> Option<char> {
     unsafe {
         let mut keyboard_state: [u8; 256] = [0; 256];
@@ -162,7 +249,8 @@ fn get_char_from_vk(vk_code: i32, shift_pressed: bool, caps_lock_on: bool) -> Op
         }
         GetKeyboardState(keyboard_state.as_mut_ptr());
 
-        let scan_code = MapVirtualKeyExW(vk_code as u32, MAPVK_VK_TO_VSC_EX, ptr::null_mut()) as u16;
+        let layout = foreground_keyboard_layout();
+        let scan_code = MapVirtualKeyExW(vk_code as u32, MAPVK_VK_TO_VSC_EX, layout) as u16;
         let mut char_buffer: [u16; 2] = [0; 2];
 
         let result = ToUnicodeEx(
@@ -172,7 +260,7 @@ fn get_char_from_vk(vk_code: i32, shift_pressed: bool, caps_lock_on: bool) -> Op
             char_buffer.as_mut_ptr(),
             2,
             0,
-            GetKeyboardLayout(0),
+            layout,
         );
 
         if result == 1 {
@@ -188,325 +276,1663 @@ fn get_char_from_vk(vk_code: i32, shift_pressed: bool, caps_lock_on: bool) -> Op
     }
 }
 
-fn check_and_replace(app_state: &AppState, current_text: &mut VecDeque<char>) -> Result<()> {
+fn check_and_replace(app_state: &AppState, current_text: &mut VecDeque<char>) -> Result<Option<PendingExpansion>> {
     let immutable_current_text: String = current_text.iter().collect();
     let config = app_state.config.lock().unwrap();
-    for rule in &config.rules {
-        for trigger in &rule.triggers {
-            if immutable_current_text.ends_with(trigger) {
-                match &rule.replacement {
-                    Replacement::Simple(text) => {
-                        perform_replacement(
-                            current_text,
-                            trigger,
-                            text,
-                            true,
-                            false,
-                            app_state,
-                        )?;
-                    }
-                    Replacement::Multiline(text) => {
-                        perform_replacement(
-                            current_text,
-                            trigger,
-                            text,
-                            false,
-                            false,
-                            app_state,
-                        )?;
-                    }
-                    Replacement::Code { language, content } => {
-                        let replacement = process_code_replacement(language, content)?;
-                        perform_replacement(
-                            current_text,
-                            trigger,
-                            &replacement,
-                            false,
-                            true,
-                            app_state,
-                        )?;
-                    }
-                }
-                return Ok(());
-            }
-        }
-    }
-    Ok(())
+    let (text, trailing) = match split_commit_char(&immutable_current_text, &config.settings) {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    expand_trigger(app_state, &config, current_text, text, trailing, false)
 }
 
-fn perform_replacement(
-    current_text: &mut VecDeque<char>,
-    original: &str,
-    replacement: &str,
-    propagate_case: bool,
-    dynamic: bool,
+/// Expands the trigger at the end of the buffer without waiting for a commit
+/// character.
+fn force_expand(app_state: &AppState, current_text: &mut VecDeque<char>) -> Result<Option<PendingExpansion>> {
+    let immutable_current_text: String = current_text.iter().collect();
+    let config = app_state.config.lock().unwrap();
+    expand_trigger(app_state, &config, current_text, &immutable_current_text, None, true)
+}
+
+/// An expansion that has to be confirmed first. The dialog is shown by
+/// `confirm_and_expand` once the buffer and config locks are released, so
+/// the main loop doesn't hold them while it waits for an answer.
+struct PendingExpansion {
+    trigger: String,
+    app: Option<String>,
+    rule: TextraRule,
+    trailing: Option<char>,
+    after: usize,
+    safe_mode: bool,
+    settings: TextraSettings,
+}
+
+/// Expands the trigger `text` ends with, if any. A `// Confirm` rule is
+/// returned instead of expanded.
+fn expand_trigger(
     app_state: &AppState,
-) -> Result<()> {
-    let final_replacement = if dynamic {
-        process_dynamic_replacement(replacement)
-    } else if propagate_case {
-        propagate_case_fn(original, replacement)
+    config: &TextraConfig,
+    current_text: &mut VecDeque<char>,
+    text: &str,
+    trailing: Option<char>,
+    force: bool,
+) -> Result<Option<PendingExpansion>> {
+    let lookup_started = Instant::now();
+    // `// Anywhere` rules look at everything typed, commit character included.
+    let typed: String = text.chars().chain(trailing).collect();
+    let found = if force {
+        find_replacement(config, text).map(|(rule, trigger)| (rule, trigger, trailing, 0))
     } else {
-        replacement.to_string()
+        find_expansion(config, text, trailing)
+            .map(|(rule, trigger, trailing)| (rule, trigger, trailing, 0))
+            .or_else(|| {
+                find_anywhere(config, &typed, Local::now().date_naive()).map(|(rule, trigger, after)| (rule, trigger, None, after))
+            })
     };
+    app_state.metrics.record_lookup(lookup_started.elapsed());
 
-    if app_state.killswitch.load(Ordering::SeqCst) {
-        return Ok(());
+    if let Some((rule, trigger, trailing, after)) = found {
+        if let Some(left) = snooze::state_path().and_then(|path| snooze::remaining(&path)) {
+            eprintln!("snoozed for {}: left '{}' alone", snooze::format_remaining(left), trigger);
+            return Ok(None);
+        }
+        let safe_mode = app_state.get_safe_mode();
+        let app = foreground_app_name();
+        match expansion_guard(&rule.options, app.as_deref(), &config.settings.secret_apps) {
+            ExpansionGuard::Allow => {}
+            ExpansionGuard::Refuse => {
+                eprintln!("'{}' is a secret and {} isn't in secret_apps, left it alone", trigger, app.unwrap_or_default());
+                return Ok(None);
+            }
+            ExpansionGuard::Confirm => {
+                return Ok(Some(PendingExpansion {
+                    trigger: trigger.to_string(),
+                    app,
+                    rule: rule.clone(),
+                    trailing,
+                    after,
+                    safe_mode,
+                    settings: config.settings.clone(),
+                }));
+            }
+        }
+        let segments = match render_rule(rule, trigger, &config.settings, safe_mode, &app_state.rotation) {
+            Some(segments) => segments,
+            None => return Ok(None),
+        };
+        let deleted = text_to_delete(trigger, rule.options.keep_prefix);
+        perform_replacement(current_text, deleted, segments, trailing, after, rule.options.secret, &config.settings, app_state)?;
     }
+    Ok(None)
+}
 
-    let backspace_count = original.chars().count();
-    let backspaces: Vec<KeyPress> = vec![KeyPress { modifiers: vec![], key: VK_BACK as i32 }; backspace_count];
-    simulate_key_presses(&backspaces, KEY_DELAY)?;
-
-    let vk_codes = string_to_vk_codes(&final_replacement, app_state.shift_pressed.load(Ordering::SeqCst), app_state.caps_lock_on.load(Ordering::SeqCst));
-    simulate_key_presses(&vk_codes, KEY_DELAY)?;
-
-    for _ in 0..original.len() {
-        current_text.pop_back();
-    }
-    for c in final_replacement.chars() {
-        current_text.push_back(c);
-        if current_text.len() > MAX_TEXT_LENGTH {
-            current_text.pop_front();
+/// What `rule` types for `trigger`: the output of its code, or its text with
+/// the next `// Rotate` option picked and any parameters filled in. `None`
+/// when nothing should be typed. It advances the rotation, so it's only
+/// called once the expansion is going ahead.
+fn render_rule(
+    rule: &TextraRule,
+    trigger: &str,
+    settings: &TextraSettings,
+    safe_mode: bool,
+    rotation_state: &Mutex<rotation::Rotation>,
+) -> Option<Vec<Segment>> {
+    let (mut replacement, mut propagate_case) = match &rule.replacement {
+        Replacement::Simple(text) => (text.clone(), true),
+        Replacement::Multiline(text) => (text.clone(), false),
+        Replacement::Code { language, .. } if safe_mode => {
+            eprintln!("safe mode: skipped '{}' ({} code is disabled)", trigger, language);
+            return None;
         }
+        Replacement::Code { language, content } => match process_code_replacement(language, content) {
+            Ok(output) => (normalize_line_endings(&output, settings.line_endings), false),
+            Err(e) => {
+                eprintln!("code for '{}' failed: {}", trigger, e);
+                (code_failure_replacement(&e, settings.on_code_failure)?, false)
+            }
+        },
+    };
+    if rule.options.rotate {
+        let options = rotation::options(&replacement);
+        let index = rotation_state.lock().unwrap().advance(&rule.triggers[0], options.len());
+        replacement = options[index].to_string();
+    }
+    if let Some(args) = invocation_args(trigger) {
+        replacement = substitute_params(&replacement, &args);
+        propagate_case = false;
     }
+    if safe_mode {
+        eprintln!("safe mode: expanding '{}'", trigger);
+    }
+    let deleted = text_to_delete(trigger, rule.options.keep_prefix);
+    Some(render_replacement(deleted, &replacement, propagate_case, rule.options.literal, safe_mode))
+}
 
-    Ok(())
+/// Asks about a `// Confirm` expansion and performs it if the answer is yes.
+/// The keys that answered the dialog went to it rather than the document, so
+/// the buffer is dropped and they aren't left waiting for `settle_delay_ms`.
+fn confirm_and_expand(app_state: &AppState, pending: PendingExpansion) -> Result<()> {
+    let confirmed = confirm_expansion(&pending.trigger, pending.app.as_deref());
+    app_state.current_text.lock().unwrap().clear();
+    KEY_DOWNS_HANDLED.store(KEY_DOWNS_SEEN.load(Ordering::SeqCst), Ordering::SeqCst);
+    if !confirmed {
+        return Ok(());
+    }
+    let rule = &pending.rule;
+    let segments = match render_rule(rule, &pending.trigger, &pending.settings, pending.safe_mode, &app_state.rotation) {
+        Some(segments) => segments,
+        None => return Ok(()),
+    };
+    let deleted = text_to_delete(&pending.trigger, rule.options.keep_prefix);
+    let mut current_text = app_state.current_text.lock().unwrap();
+    perform_replacement(
+        &mut current_text,
+        deleted,
+        segments,
+        pending.trailing,
+        pending.after,
+        rule.options.secret,
+        &pending.settings,
+        app_state,
+    )
 }
 
- 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpansionGuard {
+    Allow,
+    Confirm,
+    Refuse,
+}
 
-fn propagate_case_fn(original: &str, replacement: &str) -> String {
-    if original.chars().all(|c| c.is_uppercase()) {
-        replacement.to_uppercase()
-    } else if original.chars().next().map_or(false, |c| c.is_uppercase()) {
-        let mut chars = replacement.chars();
-        match chars.next() {
-            None => String::new(),
-            Some(first_char) => first_char.to_uppercase().collect::<String>() + chars.as_str(),
+/// Whether a rule may expand in `app`. `// Secret` rules only expand in
+/// `secret_apps` when that is set, since a focus change could otherwise put
+/// a password in a chat window; `// Confirm` rules ask first.
+fn expansion_guard(options: &RuleOptions, app: Option<&str>, secret_apps: &[String]) -> ExpansionGuard {
+    if options.secret && !secret_apps.is_empty() {
+        let allowed = app.map_or(false, |app| secret_apps.contains(&app.to_lowercase()));
+        if !allowed {
+            return ExpansionGuard::Refuse;
         }
+    }
+    if options.confirm {
+        ExpansionGuard::Confirm
     } else {
-        replacement.to_string()
+        ExpansionGuard::Allow
     }
 }
 
-fn process_dynamic_replacement(replacement: &str) -> String {
-    match replacement.to_lowercase().as_str() {
-        "{{date}}" => Local::now().format("%Y-%m-%d").to_string(),
-        "{{time}}" => Local::now().format("%H:%M:%S").to_string(),
-        _ => replacement.to_string(),
+/// Asks with a message box, then gives focus back to the window the trigger
+/// was typed in.
+fn confirm_expansion(trigger: &str, app: Option<&str>) -> bool {
+    let text = format!("Type the replacement for '{}' into {}?", trigger, app.unwrap_or("this window"));
+    let text: Vec<u16> = OsStr::new(&text).encode_wide().chain(Some(0)).collect();
+    let caption: Vec<u16> = OsStr::new("Textra").encode_wide().chain(Some(0)).collect();
+    unsafe {
+        let target = GetForegroundWindow();
+        CONFIRMING.store(true, Ordering::SeqCst);
+        let answer = MessageBoxW(
+            ptr::null_mut(),
+            text.as_ptr(),
+            caption.as_ptr(),
+            MB_YESNO | MB_ICONQUESTION | MB_TOPMOST | MB_SETFOREGROUND,
+        );
+        CONFIRMING.store(false, Ordering::SeqCst);
+        SetForegroundWindow(target);
+        thread::sleep(Duration::from_millis(PASTE_SETTLE_DELAY));
+        answer == IDYES
     }
 }
 
-fn reload_config(app_state: Arc<AppState>) -> Result<()> {
-    let mut config = app_state.config.lock().unwrap();
-    *config = load_config()?;
-    Ok(())
+/// The part of a typed trigger that gets deleted. With `keep_prefix`, leading
+/// punctuation such as the `:` in `:email` stays in the document.
+fn text_to_delete(typed: &str, keep_prefix: bool) -> &str {
+    if keep_prefix {
+        typed.trim_start_matches(|c: char| !c.is_alphanumeric())
+    } else {
+        typed
+    }
 }
 
-fn simulate_key_presses(vk_codes: &[KeyPress], key_delay: u64) -> Result<()> {
-    let delay = Duration::from_millis(key_delay);
+/// Returns the active rule with the longest trigger that `text` ends with,
+/// and the end of `text` it matched.
+pub fn find_replacement<'a, 't>(config: &'a TextraConfig, text: &'t str) -> Option<(&'a TextraRule, &'t str)> {
+    find_replacement_on(config, text, Local::now().date_naive())
+}
 
-    for key_press in vk_codes {
-        // Press all modifiers
-        for &modifier in &key_press.modifiers {
-            let mut input_down = winapi::um::winuser::INPUT {
-                type_: INPUT_KEYBOARD,
-                u: unsafe { mem::zeroed() },
-            };
-            unsafe {
-                let ki = input_down.u.ki_mut();
-                ki.wVk = modifier as u16;
-                ki.dwFlags = 0;
-            }
-            unsafe {
-                SendInput(
-                    1,
-                    &input_down as *const _ as *mut _,
-                    std::mem::size_of::<winapi::um::winuser::INPUT>() as c_int,
-                );
-            }
-            thread::sleep(delay);
-        }
+fn find_replacement_on<'a, 't>(
+    config: &'a TextraConfig,
+    text: &'t str,
+    today: NaiveDate,
+) -> Option<(&'a TextraRule, &'t str)> {
+    find_rule_on(config, text, today, |_, _| true)
+}
 
-        // Press the main key
-        let mut input_down = winapi::um::winuser::INPUT {
-            type_: INPUT_KEYBOARD,
-            u: unsafe { mem::zeroed() },
-        };
-        unsafe {
-            let ki = input_down.u.ki_mut();
-            ki.wVk = key_press.key as u16;
-            ki.dwFlags = 0;
-        }
-        unsafe {
-            SendInput(
-                1,
-                &input_down as *const _ as *mut _,
-                std::mem::size_of::<winapi::um::winuser::INPUT>() as c_int,
-            );
-        }
-        thread::sleep(delay);
-
-        // Release the main key
-        let mut input_up = winapi::um::winuser::INPUT {
-            type_: INPUT_KEYBOARD,
-            u: unsafe { mem::zeroed() },
-        };
-        unsafe {
-            let ki = input_up.u.ki_mut();
-            ki.wVk = key_press.key as u16;
-            ki.dwFlags = KEYEVENTF_KEYUP;
-        }
-        unsafe {
-            SendInput(
-                1,
-                &input_up as *const _ as *mut _,
-                std::mem::size_of::<winapi::um::winuser::INPUT>() as c_int,
-            );
-        }
-        thread::sleep(delay);
+/// Like `find_replacement`, but holds some rules back until the next key:
+/// `WordEnd` rules wait for a character that doesn't continue the word, and
+/// code rules wait while a longer trigger could still be typed, so their
+/// interpreter isn't started for a trigger the user is still typing. Also
+/// returns the character typed after the trigger, which has to be typed again.
+fn find_expansion<'a, 't>(
+    config: &'a TextraConfig,
+    text: &'t str,
+    trailing: Option<char>,
+) -> Option<(&'a TextraRule, &'t str, Option<char>)> {
+    let today = Local::now().date_naive();
+    let boundary_known = trailing.map_or(false, is_word_boundary);
+    let found = find_rule_on(config, text, today, |rule, trigger| {
+        !rule.options.anywhere
+            && (!rule.options.word_end || boundary_known)
+            && !(trailing.is_none() && is_code(rule) && longer_trigger_pending(config, text, trigger.len(), today))
+    });
+    if let Some((rule, trigger)) = found {
+        return Some((rule, trigger, trailing));
+    }
 
-        // Release all modifiers in reverse order
-        for &modifier in key_press.modifiers.iter().rev() {
-            let mut input_up = winapi::um::winuser::INPUT {
-                type_: INPUT_KEYBOARD,
-                u: unsafe { mem::zeroed() },
-            };
-            unsafe {
-                let ki = input_up.u.ki_mut();
-                ki.wVk = modifier as u16;
-                ki.dwFlags = KEYEVENTF_KEYUP;
-            }
-            unsafe {
-                SendInput(
-                    1,
-                    &input_up as *const _ as *mut _,
-                    std::mem::size_of::<winapi::um::winuser::INPUT>() as c_int,
-                );
-            }
-            thread::sleep(delay);
-        }
+    // The key just typed may be the one a held-back rule was waiting for.
+    if trailing.is_some() {
+        return None;
     }
+    let last = text.chars().last()?;
+    let before = &text[..text.len() - last.len_utf8()];
+    find_rule_on(config, before, today, |rule, trigger| {
+        let held_back = rule.options.word_end
+            || (is_code(rule) && longer_trigger_pending(config, before, trigger.len(), today));
+        !rule.options.anywhere
+            && held_back
+            && (!rule.options.word_end || is_word_boundary(last))
+            && !(is_code(rule) && longer_trigger_pending(config, text, trigger.len() + last.len_utf8(), today))
+    })
+    .map(|(rule, trigger)| (rule, trigger, Some(last)))
+}
 
-    Ok(())
+fn is_word_boundary(c: char) -> bool {
+    !c.is_alphanumeric()
 }
 
-fn string_to_vk_codes(s: &str, shift_pressed: bool, caps_lock_on: bool) -> Vec<KeyPress> {
-    s.chars().filter_map(|c| {
-        let vk_scan = unsafe { VkKeyScanW(c as u16) };
-        if vk_scan == -1 {
-            return None;
-        }
+fn is_code(rule: &TextraRule) -> bool {
+    matches!(rule.replacement, Replacement::Code { .. })
+}
 
-        let vk_code = (vk_scan & 0xFF) as i32;
-        let shift_state = (vk_scan >> 8) & 0xFF;
+/// Whether the end of `text`, at least `min_len` bytes of it, is the start of
+/// a longer trigger that more typing could still complete.
+fn longer_trigger_pending(config: &TextraConfig, text: &str, min_len: usize, today: NaiveDate) -> bool {
+    let tails: Vec<&str> = text
+        .char_indices()
+        .map(|(i, _)| &text[i..])
+        .filter(|tail| tail.len() >= min_len)
+        .collect();
+    config
+        .rules
+        .iter()
+        .filter(|rule| rule.options.is_active_on(today))
+        .flat_map(|rule| rule.triggers.iter())
+        .any(|trigger| tails.iter().any(|tail| tail.len() < trigger.len() && trigger.starts_with(tail)))
+}
 
-        let mut modifiers = Vec::new();
+/// The active rule with the longest trigger `text` ends with that `accept`
+/// takes, and the end of `text` it matched. Between triggers of the same
+/// length the rule written first wins.
+fn find_rule_on<'a, 't>(
+    config: &'a TextraConfig,
+    text: &'t str,
+    today: NaiveDate,
+    accept: impl Fn(&TextraRule, &str) -> bool,
+) -> Option<(&'a TextraRule, &'t str)> {
+    let mut candidates = config.matcher.matches(text);
+    candidates.extend(
+        config
+            .matcher
+            .parameterized()
+            .filter_map(|(index, trigger)| Some((index, matched_tail(text, trigger)?))),
+    );
+    candidates.sort_by_key(|&(index, typed)| (std::cmp::Reverse(typed.len()), index));
+    candidates
+        .into_iter()
+        .filter_map(|(index, typed)| Some((config.rules.get(index)?, typed)))
+        .filter(|(rule, _)| rule.options.is_active_on(today))
+        .find_map(|(rule, typed)| {
+            let typed = with_sigil(text, typed, config.settings.require_sigil)?;
+            let before = &text[..text.len() - typed.len()];
+            ((!rule.options.line_start || at_line_start(before)) && accept(rule, typed)).then(|| (rule, typed))
+        })
+        .or_else(|| find_abbreviation(config, text, today, &accept))
+}
 
-        if shift_state & 1 != 0 {
-            modifiers.push(VK_SHIFT as i32);
-        }
-        if shift_state & 2 != 0 {
-            modifiers.push(VK_CONTROL as i32);
-        }
-        if shift_state & 4 != 0 {
-            modifiers.push(VK_MENU as i32);
-        }
+/// Falls back to the abbreviations of triggers when `fuzzy_abbreviations` is
+/// on. They only match at the start of a word, so typing an ordinary word
+/// that happens to end in one doesn't expand it. Each end of `text` is
+/// looked up in the abbreviation map, longest first, so the cost doesn't
+/// grow with the number of rules.
+fn find_abbreviation<'a, 't>(
+    config: &'a TextraConfig,
+    text: &'t str,
+    today: NaiveDate,
+    accept: &impl Fn(&TextraRule, &str) -> bool,
+) -> Option<(&'a TextraRule, &'t str)> {
+    if config.abbreviations.is_empty() {
+        return None;
+    }
+    let (typed, trigger) = text
+        .char_indices()
+        .find_map(|(i, _)| Some((&text[i..], config.abbreviations.get(&text[i..])?)))?;
+    if text[..text.len() - typed.len()].chars().last().map_or(false, |c| !is_word_boundary(c)) {
+        return None;
+    }
+    let typed = with_sigil(text, typed, config.settings.require_sigil)?;
+    let before = &text[..text.len() - typed.len()];
+    let rule = config
+        .matcher
+        .matches(trigger)
+        .into_iter()
+        .filter(|(_, matched)| matched.len() == trigger.len())
+        .filter_map(|(index, _)| config.rules.get(index))
+        .find(|rule| rule.options.is_active_on(today))?;
+    ((!rule.options.line_start || at_line_start(before)) && accept(rule, typed)).then(|| (rule, typed))
+}
 
-        if shift_pressed || caps_lock_on {
-            SYMBOL_PAIRS.get(&c).cloned().map(|symbol| KeyPress {
-                modifiers: modifiers.clone(),
-                key: symbol as i32,
-            })
-        } else {
-            Some(KeyPress {
-                modifiers,
-                key: vk_code,
-            })
-        }
-    }).collect()
+/// Finds the `// Anywhere` trigger nearest the cursor that stands as a whole
+/// word on the current line and has been finished by a boundary character.
+/// Returns the rule, the trigger as typed and how many characters were typed
+/// after it. Only the current line is searched, so nothing is changed that
+/// the cursor can't reach with the arrow keys. Triggers are looked up in the
+/// index at each boundary, nearest the cursor first.
+fn find_anywhere<'a, 't>(
+    config: &'a TextraConfig,
+    text: &'t str,
+    today: NaiveDate,
+) -> Option<(&'a TextraRule, &'t str, usize)> {
+    let line_start = text.rfind(|c| c == '\r' || c == '\n').map_or(0, |i| i + 1);
+    text[line_start..]
+        .char_indices()
+        .rev()
+        .filter(|&(_, c)| is_word_boundary(c))
+        .find_map(|(i, _)| {
+            let end = line_start + i;
+            config
+                .matcher
+                .matches(&text[..end])
+                .into_iter()
+                .filter(|(_, typed)| end - typed.len() >= line_start)
+                .filter_map(|(index, typed)| Some((config.rules.get(index)?, typed)))
+                .find(|(rule, typed)| {
+                    let starts_word = text[..end - typed.len()].chars().last().map_or(true, is_word_boundary);
+                    rule.options.anywhere && rule.options.is_active_on(today) && starts_word
+                })
+                .map(|(rule, typed)| (rule, typed, text[end..].chars().count()))
+        })
 }
 
-pub fn run_hook() -> Result<()> {
-    let (sender, receiver) = std::sync::mpsc::channel();
-    let app_state = Arc::new(AppState::new()?);
+/// Extends a match at the end of `text` over the `require_sigil` character
+/// in front of it, so the sigil is deleted along with the trigger. Without
+/// the sigil there is no match.
+fn with_sigil<'t>(text: &'t str, typed: &'t str, sigil: Option<char>) -> Option<&'t str> {
+    let start = text.len() - typed.len();
+    match sigil {
+        None => Some(typed),
+        Some(sigil) => text[..start].ends_with(sigil).then(|| &text[start - sigil.len_utf8()..]),
+    }
+}
 
-    let config_watcher_sender = sender.clone();
-    let config_watcher_handle = std::thread::spawn(move || {
-        if let Err(e) = watch_config(config_watcher_sender) {
-            eprintln!("Error watching config: {}", e);
+/// The end of `text` that `trigger` matches. A trigger ending in `()` takes
+/// parameters and matches a call such as `:link(google.com|Google)`.
+fn matched_tail<'t>(text: &'t str, trigger: &str) -> Option<&'t str> {
+    match trigger.strip_suffix("()") {
+        Some(name) => {
+            let args_end = text.strip_suffix(')')?.len();
+            let start = text[..args_end].rfind(&format!("{name}("))?;
+            let args = &text[start + name.len() + 1..args_end];
+            let nested = args.contains(|c| c == '(' || c == ')' || c == '\r' || c == '\n');
+            (!nested).then(|| &text[start..])
         }
-    });
+        None => text.ends_with(trigger).then(|| &text[text.len() - trigger.len()..]),
+    }
+}
 
-    let keyboard_listener_handle = std::thread::spawn(move || {
-        if let Err(e) = listen_keyboard(sender) {
-            eprintln!("Error in keyboard listener: {}", e);
-        }
-    });
+/// The `|`-separated arguments of a call such as `:link(google.com|Google)`,
+/// or `None` for an ordinary trigger.
+fn invocation_args(typed: &str) -> Option<Vec<&str>> {
+    let (_, args) = typed.strip_suffix(')')?.split_once('(')?;
+    Some(args.split('|').collect())
+}
 
-    main_loop(app_state, &receiver)?;
+/// Whether `before` (the buffer up to a trigger) ends with a line break and
+/// optional indentation. Without a line break in the buffer we can't tell
+/// where the line started, so that doesn't count.
+fn at_line_start(before: &str) -> bool {
+    match before.rfind(|c| c == '\r' || c == '\n') {
+        Some(i) => before[i + 1..].chars().all(char::is_whitespace),
+        None => false,
+    }
+}
 
-    config_watcher_handle.join().unwrap();
-    keyboard_listener_handle.join().unwrap();
+/// Splits off the character that was just typed when expansions wait for a
+/// commit character. Returns `None` if nothing should be expanded yet.
+fn split_commit_char<'a>(text: &'a str, settings: &TextraSettings) -> Option<(&'a str, Option<char>)> {
+    if !settings.expand_on_commit {
+        return Some((text, None));
+    }
+    let last = text.chars().last()?;
+    if !settings.is_commit_char(last) {
+        return None;
+    }
+    Some((&text[..text.len() - last.len_utf8()], Some(last)))
+}
 
-    Ok(())
+/// Turns a replacement into what gets typed. Literal rules are typed
+/// exactly; safe mode still matches case but leaves tokens alone.
+fn render_replacement(
+    original: &str,
+    replacement: &str,
+    propagate_case: bool,
+    literal: bool,
+    safe_mode: bool,
+) -> Vec<Segment> {
+    let text = render_text(original, replacement, propagate_case, literal, safe_mode);
+    if literal || safe_mode {
+        vec![Segment::Text(text)]
+    } else {
+        split_key_tokens(&text)
+    }
+}
+
+/// The replacement with tokens filled in and case matched, but key tokens
+/// such as `{{enter}}` still written out.
+fn render_text(original: &str, replacement: &str, propagate_case: bool, literal: bool, safe_mode: bool) -> String {
+    if literal {
+        return replacement.to_string();
+    }
+
+    let mut text = if safe_mode {
+        replacement.to_string()
+    } else {
+        process_dynamic_replacement(replacement)
+    };
+    if propagate_case {
+        text = propagate_case_fn(original, &text);
+    }
+    text
 }
 
+/// What `textra match` finds at the end of a piece of text.
+#[derive(Debug, PartialEq)]
+pub struct MatchReport {
+    /// Every active trigger the text ends with, in config order. Only the
+    /// first one a rule accepts expands; the others are shadowed by it.
+    pub candidates: Vec<String>,
+    /// The rule that would expand, if any.
+    pub fired: Option<MatchedRule>,
+}
 
+#[derive(Debug, PartialEq)]
+pub struct MatchedRule {
+    pub triggers: Vec<String>,
+    /// The end of the text the rule matched, e.g. `:link(a|b)`.
+    pub typed: String,
+    /// What would be typed instead. `None` for code rules, which aren't run.
+    pub replacement: Option<String>,
+}
 
+/// Works out which rule would expand if `text` had just been typed, without
+/// typing anything.
+pub fn explain_match(config: &TextraConfig, text: &str) -> MatchReport {
+    let today = Local::now().date_naive();
+    let split = split_commit_char(text, &config.settings);
+    let candidates = match split {
+        Some((text, _)) => config
+            .rules
+            .iter()
+            .filter(|rule| rule.options.is_active_on(today))
+            .flat_map(|rule| rule.triggers.iter())
+            // Without the sigil in front the daemon wouldn't fire them.
+            .filter(|trigger| {
+                matched_tail(text, trigger)
+                    .and_then(|typed| with_sigil(text, typed, config.settings.require_sigil))
+                    .is_some()
+            })
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+    let fired = split
+        .and_then(|(text, trailing)| find_expansion(config, text, trailing))
+        .map(|(rule, typed, _)| MatchedRule {
+            triggers: rule.triggers.clone(),
+            typed: typed.to_string(),
+            replacement: preview_replacement(rule, typed),
+        });
+    MatchReport { candidates, fired }
+}
 
+fn preview_replacement(rule: &TextraRule, typed: &str) -> Option<String> {
+    if rule.options.secret {
+        return Some(SECRET_MASK.to_string());
+    }
+    let (mut replacement, mut propagate_case) = match &rule.replacement {
+        Replacement::Simple(text) => (text.clone(), true),
+        Replacement::Multiline(text) => (text.clone(), false),
+        Replacement::Code { .. } => return None,
+    };
+    if rule.options.rotate {
+        replacement = rotation::options(&replacement)[0].to_string();
+    }
+    if let Some(args) = invocation_args(typed) {
+        replacement = substitute_params(&replacement, &args);
+        propagate_case = false;
+    }
+    let deleted = text_to_delete(typed, rule.options.keep_prefix);
+    Some(render_text(deleted, &replacement, propagate_case, rule.options.literal, false))
+}
 
+fn perform_replacement(
+    current_text: &mut VecDeque<char>,
+    original: &str,
+    segments: Vec<Segment>,
+    trailing: Option<char>,
+    after: usize,
+    secret: bool,
+    settings: &TextraSettings,
+    app_state: &AppState,
+) -> Result<()> {
+    if app_state.killswitch.load(Ordering::SeqCst) {
+        return Ok(());
+    }
 
+    // Don't delete the trigger if the replacement would never arrive.
+    if !foreground_accepts_input() {
+        eprintln!(
+            "Skipped '{}': the focused window belongs to an app running as administrator. Run textra as administrator to expand there.",
+            original
+        );
+        return Ok(());
+    }
 
+    if settings.settle_delay_ms > 0 {
+        let settle = Duration::from_millis(settings.settle_delay_ms);
+        if !wait_for_quiet(settle, pending_key_downs, Instant::now, thread::sleep) {
+            eprintln!("Skipped '{}': typing went on right after the trigger.", original);
+            return Ok(());
+        }
+    }
 
+    let cancel_key = cancel_key_code(settings);
+    // A commit character typed after the trigger is removed with it and
+    // typed again after the replacement.
+    let trailing: String = trailing.into_iter().collect();
+    let backspace_count = original.chars().count() + trailing.chars().count();
+    let backspaces = deletion_keys(dismiss_key_code(settings), backspace_count);
 
+    let typed_text: String = segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Text(text) => Some(text.as_str()),
+            Segment::Key(_) => None,
+        })
+        .collect();
 
- 
-static mut GLOBAL_SENDER: Option<std::sync::mpsc::Sender<Message>> = None;
-static GENERATING: AtomicBool = AtomicBool::new(false);
+    // A trigger further back is reached with the arrow keys, and the cursor
+    // goes back to where it was afterwards.
+    let typing = Typing::of(settings);
+    let arrows = |key| vec![KeyPress { modifiers: vec![], key, unicode: None }; after];
+    let mut completed = simulate_key_presses(&arrows(VK_LEFT), KEY_DELAY, cancel_key, typing)?;
+    if completed {
+        completed = simulate_key_presses(&backspaces, KEY_DELAY, cancel_key, typing)?;
+    }
+    if completed {
+        let paste = should_paste(&typed_text, secret, settings.paste_threshold, clipboard::content);
+        completed = type_segments(&segments, paste, cancel_key, typing, app_state)?;
+    }
+    if completed && !trailing.is_empty() {
+        let vk_codes = string_to_vk_codes(&trailing, app_state.shift_pressed.load(Ordering::SeqCst), app_state.caps_lock_on.load(Ordering::SeqCst));
+        completed = simulate_key_presses(&vk_codes, KEY_DELAY, cancel_key, typing)?;
+    }
+    if completed {
+        completed = simulate_key_presses(&arrows(VK_RIGHT), KEY_DELAY, cancel_key, typing)?;
+    }
 
-unsafe extern "system" fn keyboard_hook_proc(
-    code: i32,
-    w_param: WPARAM,
-    l_param: LPARAM,
-) -> LRESULT {
-    if code >= 0 && !GENERATING.load(Ordering::SeqCst) {
-        let kb_struct = *(l_param as *const KBDLLHOOKSTRUCT);
-        let vk_code = kb_struct.vkCode;
+    if !completed {
+        // Whatever was typed before the cancel stays in the document, so the
+        // buffer no longer reflects it.
+        current_text.clear();
+        return Ok(());
+    }
 
-        if let Some(sender) = &GLOBAL_SENDER {
-            let _ = sender.send(Message::KeyEvent(vk_code, w_param, l_param));
-        }
+    let inserted: String = typed_text.chars().chain(trailing.chars()).collect();
+    splice_buffer(current_text, backspace_count, &inserted, after);
+    app_state.metrics.record_expansion();
+    if settings.expansion_log {
+        let logged_text = if secret { SECRET_MASK } else { &typed_text };
+        log_expansion(original, logged_text, settings.expansion_log_text);
     }
 
-    CallNextHookEx(ptr::null_mut(), code, w_param, l_param)
+    Ok(())
 }
 
-pub fn listen_keyboard(sender: std::sync::mpsc::Sender<Message>) -> Result<()> {
-    unsafe {
-        GLOBAL_SENDER = Some(sender);
+/// Replaces the `deleted` characters that come `after` characters before the
+/// end of the buffer with `inserted`.
+fn splice_buffer(current_text: &mut VecDeque<char>, deleted: usize, inserted: &str, after: usize) {
+    let tail = current_text.split_off(current_text.len().saturating_sub(after));
+    for _ in 0..deleted {
+        current_text.pop_back();
     }
-    
-    unsafe {
-        let hook = SetWindowsHookExA(WH_KEYBOARD_LL, Some(keyboard_hook_proc), ptr::null_mut(), 0);
-        if hook.is_null() {
-            return Err(anyhow::anyhow!("Failed to set keyboard hook: {}", std::io::Error::last_os_error()));
-        }
-        let mut msg: MSG = mem::zeroed();
-        while GetMessageA(&mut msg, ptr::null_mut(), 0, 0) > 0 {
-            TranslateMessage(&msg);
-            DispatchMessageA(&msg);
+    for c in inserted.chars().chain(tail) {
+        current_text.push_back(c);
+        if current_text.len() > MAX_TEXT_LENGTH {
+            current_text.pop_front();
         }
-        UnhookWindowsHookEx(hook);
     }
-    Ok(())
 }
- 
-#[derive(Debug, Clone)]
-struct KeyPress {
-    modifiers: Vec<i32>, // e.g., VK_SHIFT, VK_CONTROL, VK_MENU
+
+fn log_expansion(trigger: &str, typed_text: &str, keep_text: bool) {
+    let path = match expansion_log::log_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let app = foreground_app_name().unwrap_or_default();
+    let entry = ExpansionLogEntry::new(Local::now(), trigger, &app, typed_text, keep_text);
+    if let Err(e) = expansion_log::append(&path, &entry) {
+        eprintln!("Failed to write expansion log: {}", e);
+    }
+}
+
+ 
+
+fn reload_config(app_state: Arc<AppState>) -> Result<()> {
+    let mut config = app_state.config.lock().unwrap();
+    *config = load_config()?;
+    Ok(())
+}
+
+fn split_key_tokens(text: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut last = 0;
+    for caps in KEY_TOKEN.captures_iter(text) {
+        let token = caps.get(0).unwrap();
+        if token.start() > last {
+            segments.push(Segment::Text(text[last..token.start()].to_string()));
+        }
+        match parse_key_token(&caps[1]) {
+            Some((key, count)) => segments.extend(std::iter::repeat(Segment::Key(key)).take(count)),
+            None => eprintln!("Unknown key in '{}', skipping it", token.as_str()),
+        }
+        last = token.end();
+    }
+    if last < text.len() {
+        segments.push(Segment::Text(text[last..].to_string()));
+    }
+    segments
+}
+
+/// Parses `enter`, `tab` or `key:NAME[:COUNT]` into a key code and how many
+/// times to press it.
+fn parse_key_token(token: &str) -> Option<(i32, usize)> {
+    let token = token.trim();
+    let spec = match token.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("key:") => &token[4..],
+        _ => token,
+    };
+    let (name, count) = match spec.split_once(':') {
+        Some((name, count)) => (name, count.trim().parse::<usize>().ok()?),
+        None => (spec, 1),
+    };
+    Some((key_code_from_name(name)?, count.min(MAX_KEY_REPEAT)))
+}
+
+fn type_segments(segments: &[Segment], paste: bool, cancel_key: i32, typing: Typing, app_state: &AppState) -> Result<bool> {
+    for segment in segments {
+        let completed = match segment {
+            Segment::Text(text) if paste => paste_text(text, cancel_key, typing)?,
+            Segment::Text(text) => {
+                let vk_codes = string_to_vk_codes(text, app_state.shift_pressed.load(Ordering::SeqCst), app_state.caps_lock_on.load(Ordering::SeqCst));
+                simulate_key_presses(&vk_codes, KEY_DELAY, cancel_key, typing)?
+            }
+            Segment::Key(key) => simulate_key_presses(&[KeyPress { modifiers: vec![], key: *key, unicode: None }], KEY_DELAY, cancel_key, typing)?,
+        };
+        if !completed {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// How simulated keys are sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Typing {
+    input_mode: InputMode,
+    /// Each pause between key events varies by up to this many milliseconds
+    /// either way. `0` keeps them all the same.
+    jitter_ms: u64,
+}
+
+impl Typing {
+    const PLAIN: Typing = Typing { input_mode: InputMode::VirtualKey, jitter_ms: 0 };
+
+    fn of(settings: &TextraSettings) -> Self {
+        Self { input_mode: settings.input_mode, jitter_ms: settings.humanize_jitter_ms }
+    }
+
+    fn pause(&self, key_delay: u64) -> Duration {
+        jittered_delay(key_delay, self.jitter_ms, next_random())
+    }
+}
+
+/// `key_delay` moved by up to `jitter_ms` either way, picked by `random`.
+fn jittered_delay(key_delay: u64, jitter_ms: u64, random: u64) -> Duration {
+    if jitter_ms == 0 {
+        return Duration::from_millis(key_delay);
+    }
+    let offset = random % (2 * jitter_ms + 1);
+    Duration::from_millis((key_delay + offset).saturating_sub(jitter_ms))
+}
+
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// A xorshift generator, which is plenty to vary key timing.
+fn next_random() -> u64 {
+    let mut x = RANDOM_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0x9E37_79B9_7F4A_7C15, |elapsed| elapsed.as_nanos() as u64)
+            | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RANDOM_STATE.store(x, Ordering::Relaxed);
+    x
+}
+
+/// Sends the key presses in order. Returns `Ok(false)` if `cancel_key` was held
+/// down before every key press had been sent.
+fn simulate_key_presses(vk_codes: &[KeyPress], key_delay: u64, cancel_key: i32, typing: Typing) -> Result<bool> {
+    for key_press in vk_codes {
+        if is_key_down(cancel_key) {
+            return Ok(false);
+        }
+
+        if let Some(c) = key_press.unicode {
+            send_unicode_char(c);
+            thread::sleep(typing.pause(key_delay));
+            continue;
+        }
+
+        // Press all modifiers
+        for &modifier in &key_press.modifiers {
+            send_key_event(modifier, false, typing.input_mode);
+            thread::sleep(typing.pause(key_delay));
+        }
+
+        // Press and release the main key
+        send_key_event(key_press.key, false, typing.input_mode);
+        thread::sleep(typing.pause(key_delay));
+        send_key_event(key_press.key, true, typing.input_mode);
+        thread::sleep(typing.pause(key_delay));
+
+        // Release all modifiers in reverse order
+        for &modifier in key_press.modifiers.iter().rev() {
+            send_key_event(modifier, true, typing.input_mode);
+            thread::sleep(typing.pause(key_delay));
+        }
+    }
+
+    Ok(true)
+}
+
+fn send_key_event(vk_code: i32, key_up: bool, input_mode: InputMode) {
+    let fields = key_event_fields(vk_code, key_up, input_mode, layout_scan_code);
+    let mut input = winapi::um::winuser::INPUT {
+        type_: INPUT_KEYBOARD,
+        u: unsafe { mem::zeroed() },
+    };
+    unsafe {
+        let ki = input.u.ki_mut();
+        ki.wVk = fields.vk;
+        ki.wScan = fields.scan;
+        ki.dwFlags = fields.flags;
+        SendInput(
+            1,
+            &mut input,
+            std::mem::size_of::<winapi::um::winuser::INPUT>() as c_int,
+        );
+    }
+}
+
+/// Types `c` whatever the keyboard layout is, by pressing and releasing
+/// it as a code point.
+fn send_unicode_char(c: char) {
+    let mut inputs = char_to_unicode_inputs(c);
+    let releases: Vec<INPUT> = inputs
+        .iter()
+        .map(|input| {
+            let mut input = *input;
+            unsafe { input.u.ki_mut().dwFlags |= KEYEVENTF_KEYUP };
+            input
+        })
+        .collect();
+    inputs.extend(releases);
+    unsafe {
+        SendInput(inputs.len() as UINT, inputs.as_mut_ptr(), mem::size_of::<INPUT>() as c_int);
+    }
+}
+
+/// The key-down events that type `c` as a code point: one for characters
+/// in the Basic Multilingual Plane, and one per half of the surrogate pair
+/// for the rest, such as most emoji.
+fn char_to_unicode_inputs(c: char) -> Vec<INPUT> {
+    let mut units = [0u16; 2];
+    c.encode_utf16(&mut units)
+        .iter()
+        .map(|&unit| {
+            let mut input = INPUT { type_: INPUT_KEYBOARD, u: unsafe { mem::zeroed() } };
+            unsafe {
+                let ki = input.u.ki_mut();
+                ki.wVk = 0;
+                ki.wScan = unit;
+                ki.dwFlags = KEYEVENTF_UNICODE;
+            }
+            input
+        })
+        .collect()
+}
+
+/// The `wVk`, `wScan` and `dwFlags` of a simulated key event.
+#[derive(Debug, PartialEq)]
+struct KeyEventFields {
+    vk: u16,
+    scan: u16,
+    flags: DWORD,
+}
+
+/// Fills in a key event for `input_mode`. Scan codes come from `scan_code`,
+/// which returns them the way `MapVirtualKeyEx` does with
+/// `MAPVK_VK_TO_VSC_EX`: keys such as the arrows carry an `0xE0` prefix and
+/// have to be sent as extended keys.
+fn key_event_fields(vk_code: i32, key_up: bool, input_mode: InputMode, scan_code: impl Fn(i32) -> u32) -> KeyEventFields {
+    let up = if key_up { KEYEVENTF_KEYUP } else { 0 };
+    match input_mode {
+        InputMode::VirtualKey => KeyEventFields { vk: vk_code as u16, scan: 0, flags: up },
+        InputMode::ScanCode => {
+            let scan = scan_code(vk_code);
+            let extended = if matches!(scan >> 8, 0xE0 | 0xE1) { KEYEVENTF_EXTENDEDKEY } else { 0 };
+            KeyEventFields { vk: 0, scan: (scan & 0xFF) as u16, flags: KEYEVENTF_SCANCODE | extended | up }
+        }
+    }
+}
+
+/// The scan code of a virtual key in the focused window's keyboard layout.
+fn layout_scan_code(vk_code: i32) -> u32 {
+    unsafe { MapVirtualKeyExW(vk_code as UINT, MAPVK_VK_TO_VSC_EX, foreground_keyboard_layout()) }
+}
+
+/// The keyboard layout of the focused window. Layouts are per thread, and
+/// the daemon's own thread keeps the one it started with.
+fn foreground_keyboard_layout() -> HKL {
+    unsafe {
+        let thread_id = GetWindowThreadProcessId(GetForegroundWindow(), ptr::null_mut());
+        GetKeyboardLayout(thread_id)
+    }
+}
+
+fn is_key_down(vk_code: i32) -> bool {
+    // The main loop is busy while typing, so the hook's key events for the
+    // cancel key are not processed until afterwards; ask the OS directly.
+    unsafe { GetAsyncKeyState(vk_code) as u16 & 0x8000 != 0 }
+}
+
+#[link(name = "imm32")]
+extern "system" {
+    fn ImmGetDefaultIMEWnd(hwnd: HWND) -> HWND;
+}
+
+const IMC_GETOPENSTATUS: WPARAM = 0x0005;
+const IME_QUERY_TIMEOUT_MS: UINT = 50;
+
+/// Whether the focused window has its IME switched on, so keystrokes go into
+/// a composition instead of straight into the document. A low-level hook
+/// never sees the composition messages, so ask the window's IME window.
+fn ime_composing() -> bool {
+    unsafe {
+        let foreground = GetForegroundWindow();
+        if foreground.is_null() {
+            return false;
+        }
+        let ime_window = ImmGetDefaultIMEWnd(foreground);
+        if ime_window.is_null() {
+            return false;
+        }
+        let mut open: DWORD_PTR = 0;
+        let sent = SendMessageTimeoutW(
+            ime_window,
+            WM_IME_CONTROL,
+            IMC_GETOPENSTATUS,
+            0,
+            SMTO_ABORTIFHUNG,
+            IME_QUERY_TIMEOUT_MS,
+            &mut open,
+        );
+        sent != 0 && open != 0
+    }
+}
+
+/// The executable name of the focused window's process, e.g. `notepad.exe`.
+fn foreground_app_name() -> Option<String> {
+    window_app_name(unsafe { GetForegroundWindow() })
+}
+
+/// The executable name of the process `hwnd` belongs to.
+fn window_app_name(hwnd: HWND) -> Option<String> {
+    unsafe {
+        if hwnd.is_null() {
+            return None;
+        }
+        let mut process_id: DWORD = 0;
+        GetWindowThreadProcessId(hwnd, &mut process_id);
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id);
+        if process.is_null() {
+            return None;
+        }
+
+        let mut buffer = [0u16; MAX_PATH];
+        let mut size = buffer.len() as DWORD;
+        let ok = QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut size) != 0;
+        CloseHandle(process);
+        if !ok {
+            return None;
+        }
+        let path = String::from_utf16_lossy(&buffer[..size as usize]);
+        Path::new(&path).file_name().map(|name| name.to_string_lossy().into_owned())
+    }
+}
+
+/// Windows silently drops input sent to a process with a higher integrity
+/// level than ours (UIPI), e.g. an elevated app when textra is not elevated.
+fn foreground_accepts_input() -> bool {
+    unsafe {
+        let hwnd = GetForegroundWindow();
+        if hwnd.is_null() {
+            return true;
+        }
+        let mut process_id: DWORD = 0;
+        GetWindowThreadProcessId(hwnd, &mut process_id);
+
+        let own_level = match process_integrity_level(GetCurrentProcess()) {
+            Some(level) => level,
+            None => return true,
+        };
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id);
+        if process.is_null() {
+            return true;
+        }
+        // A non-elevated process is not allowed to read an elevated process's
+        // token, so failing here means the target is elevated.
+        let target_level = process_integrity_level(process).unwrap_or(SECURITY_MANDATORY_HIGH_RID);
+        CloseHandle(process);
+
+        can_send_input(own_level, target_level)
+    }
+}
+
+fn can_send_input(own_level: DWORD, target_level: DWORD) -> bool {
+    target_level <= own_level
+}
+
+unsafe fn process_integrity_level(process: HANDLE) -> Option<DWORD> {
+    let mut token: HANDLE = ptr::null_mut();
+    if OpenProcessToken(process, TOKEN_QUERY, &mut token) == 0 {
+        return None;
+    }
+
+    let mut size: DWORD = 0;
+    GetTokenInformation(token, TokenIntegrityLevel, ptr::null_mut(), 0, &mut size);
+    // u64 storage keeps the TOKEN_MANDATORY_LABEL pointer-aligned.
+    let mut buffer = vec![0u64; (size as usize + 7) / 8];
+    let level = if size > 0
+        && GetTokenInformation(token, TokenIntegrityLevel, buffer.as_mut_ptr() as LPVOID, size, &mut size) != 0
+    {
+        let label = &*(buffer.as_ptr() as *const TOKEN_MANDATORY_LABEL);
+        let count = *GetSidSubAuthorityCount(label.Label.Sid);
+        Some(*GetSidSubAuthority(label.Label.Sid, count as DWORD - 1))
+    } else {
+        None
+    };
+
+    CloseHandle(token);
+    level
+}
+
+fn cancel_key_code(settings: &TextraSettings) -> i32 {
+    key_code_from_name(&settings.cancel_key).unwrap_or(VK_ESCAPE)
+}
+
+fn force_expand_key_code(settings: &TextraSettings) -> Option<i32> {
+    settings.force_expand_key.as_deref().and_then(key_code_from_name)
+}
+
+/// The keys that remove a trigger: the dismiss key if there is one, then a
+/// backspace per character.
+fn deletion_keys(dismiss_key: Option<i32>, count: usize) -> Vec<KeyPress> {
+    let backspace = KeyPress { modifiers: vec![], key: VK_BACK as i32, unicode: None };
+    dismiss_key
+        .map(|key| KeyPress { modifiers: vec![], key, unicode: None })
+        .into_iter()
+        .chain(std::iter::repeat(backspace).take(count))
+        .collect()
+}
+
+fn dismiss_key_code(settings: &TextraSettings) -> Option<i32> {
+    let key = key_code_from_name(settings.dismiss_key.as_deref()?)?;
+    if settings.dismiss_apps.is_empty() {
+        return Some(key);
+    }
+    let app = foreground_app_name()?.to_lowercase();
+    settings.dismiss_apps.contains(&app).then(|| key)
+}
+
+/// Key presses the hook has passed on that the main loop hasn't handled yet,
+/// i.e. ones typed after the key being handled now.
+fn pending_key_downs() -> usize {
+    KEY_DOWNS_SEEN.load(Ordering::SeqCst).saturating_sub(KEY_DOWNS_HANDLED.load(Ordering::SeqCst))
+}
+
+/// Waits `settle` for typing to stop after a trigger. Returns `false` as soon
+/// as another key goes down: that key lands in the document before any
+/// backspaces do, so deleting the trigger would eat it instead.
+fn wait_for_quiet(
+    settle: Duration,
+    pending: impl Fn() -> usize,
+    now: impl Fn() -> Instant,
+    sleep: impl Fn(Duration),
+) -> bool {
+    let deadline = now() + settle;
+    loop {
+        if pending() > 0 {
+            return false;
+        }
+        let current = now();
+        if current >= deadline {
+            return true;
+        }
+        sleep((deadline - current).min(SETTLE_POLL));
+    }
+}
+
+/// Whether `text` is long enough to paste. Only text can be put back on the
+/// clipboard afterwards, so with an image or files on it `text` is typed
+/// instead; `read_clipboard` is only called for a long text. Secrets are
+/// always typed, since clipboard managers keep a history of what was pasted.
+fn should_paste(text: &str, secret: bool, paste_threshold: usize, read_clipboard: impl FnOnce() -> clipboard::Content) -> bool {
+    !secret
+        && paste_threshold > 0
+        && text.chars().count() > paste_threshold
+        && read_clipboard() != clipboard::Content::Other
+}
+
+/// Pastes `text` with Ctrl+V, then puts back whatever text was on the
+/// clipboard before, or empties it if there was none.
+fn paste_text(text: &str, cancel_key: i32, typing: Typing) -> Result<bool> {
+    let previous = clipboard::get_text();
+    clipboard::set_text(text)?;
+
+    let paste = KeyPress { modifiers: vec![VK_CONTROL], key: 'V' as i32, unicode: None };
+    let completed = simulate_key_presses(&[paste], KEY_DELAY, cancel_key, typing)?;
+
+    // The target app reads the clipboard asynchronously after Ctrl+V.
+    thread::sleep(Duration::from_millis(PASTE_SETTLE_DELAY));
+    match previous {
+        Some(previous) => clipboard::set_text(&previous)?,
+        None => clipboard::clear()?,
+    }
+
+    Ok(completed)
+}
+
+/// Maps a key name such as `escape`, `enter`, `home` or `f5` to its virtual
+/// key code.
+pub fn key_code_from_name(name: &str) -> Option<i32> {
+    let code = match name.trim().to_lowercase().as_str() {
+        "escape" | "esc" => VK_ESCAPE,
+        "enter" | "return" => VK_RETURN,
+        "tab" => VK_TAB,
+        "space" => VK_SPACE,
+        "backspace" => VK_BACK,
+        "delete" | "del" => VK_DELETE,
+        "insert" | "ins" => VK_INSERT,
+        "home" => VK_HOME,
+        "end" => VK_END,
+        "pageup" => VK_PRIOR,
+        "pagedown" => VK_NEXT,
+        "left" => VK_LEFT,
+        "right" => VK_RIGHT,
+        "up" => VK_UP,
+        "down" => VK_DOWN,
+        "pause" => VK_PAUSE,
+        "scrolllock" => VK_SCROLL,
+        other => {
+            let n = other.strip_prefix('f')?.parse::<i32>().ok()?;
+            if !(1..=24).contains(&n) {
+                return None;
+            }
+            VK_F1 + n - 1
+        }
+    };
+    Some(code)
+}
+
+fn string_to_vk_codes(s: &str, shift_pressed: bool, caps_lock_on: bool) -> Vec<KeyPress> {
+    // Whatever the line ending, a line break is one plain Enter. VkKeyScanW
+    // maps `\n` to Ctrl+Enter, which sends messages in many apps.
+    s.replace("\r\n", "\n").chars().filter_map(|c| {
+        if c == '\n' || c == '\r' {
+            return Some(KeyPress { modifiers: vec![], key: VK_RETURN, unicode: None });
+        }
+
+        // Characters the layout has no key for, and those outside the
+        // Basic Multilingual Plane that VkKeyScanW can't even be asked
+        // about, are sent as their code point.
+        let vk_scan = match u16::try_from(c as u32) {
+            Ok(unit) => unsafe { VkKeyScanW(unit) },
+            Err(_) => -1,
+        };
+        if vk_scan == -1 {
+            return Some(KeyPress { modifiers: vec![], key: 0, unicode: Some(c) });
+        }
+
+        let vk_code = (vk_scan & 0xFF) as i32;
+        let shift_state = (vk_scan >> 8) & 0xFF;
+
+        let mut modifiers = Vec::new();
+
+        if shift_state & 1 != 0 {
+            modifiers.push(VK_SHIFT as i32);
+        }
+        if shift_state & 2 != 0 {
+            modifiers.push(VK_CONTROL as i32);
+        }
+        if shift_state & 4 != 0 {
+            modifiers.push(VK_MENU as i32);
+        }
+
+        if shift_pressed || caps_lock_on {
+            SYMBOL_PAIRS.get(&c).cloned().map(|symbol| KeyPress {
+                modifiers: modifiers.clone(),
+                key: symbol as i32,
+                unicode: None,
+            })
+        } else {
+            Some(KeyPress {
+                modifiers,
+                key: vk_code,
+                unicode: None,
+            })
+        }
+    }).collect()
+}
+
+pub fn run_hook() -> Result<()> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let app_state = Arc::new(AppState::new()?);
+
+    let config_watcher_sender = sender.clone();
+    let config_watcher_handle = std::thread::spawn(move || {
+        if let Err(e) = watch_config(config_watcher_sender) {
+            eprintln!("Error watching config: {}", e);
+        }
+    });
+
+    let keyboard_listener_handle = std::thread::spawn(move || {
+        if let Err(e) = listen_keyboard(sender) {
+            eprintln!("Error in keyboard listener: {}", e);
+        }
+    });
+
+    main_loop(app_state, &receiver)?;
+
+    config_watcher_handle.join().unwrap();
+    keyboard_listener_handle.join().unwrap();
+
+    Ok(())
+}
+
+
+
+
+
+
+
+
+
+
+ 
+static mut GLOBAL_SENDER: Option<std::sync::mpsc::Sender<Message>> = None;
+static GENERATING: AtomicBool = AtomicBool::new(false);
+/// Set while a `// Confirm` dialog is open, so the keys that answer it
+/// aren't added to the buffer afterwards.
+static CONFIRMING: AtomicBool = AtomicBool::new(false);
+static KEY_DOWNS_SEEN: AtomicUsize = AtomicUsize::new(0);
+static KEY_DOWNS_HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "system" fn keyboard_hook_proc(
+    code: i32,
+    w_param: WPARAM,
+    l_param: LPARAM,
+) -> LRESULT {
+    if code >= 0 && !GENERATING.load(Ordering::SeqCst) && !CONFIRMING.load(Ordering::SeqCst) {
+        let kb_struct = *(l_param as *const KBDLLHOOKSTRUCT);
+        let vk_code = kb_struct.vkCode;
+        if w_param as u32 == WM_KEYDOWN || w_param as u32 == WM_SYSKEYDOWN {
+            KEY_DOWNS_SEEN.fetch_add(1, Ordering::SeqCst);
+        }
+
+        if let Some(sender) = &GLOBAL_SENDER {
+            let _ = sender.send(Message::KeyEvent(vk_code, w_param, l_param));
+        }
+    }
+
+    CallNextHookEx(ptr::null_mut(), code, w_param, l_param)
+}
+
+unsafe extern "system" fn mouse_hook_proc(
+    code: i32,
+    w_param: WPARAM,
+    l_param: LPARAM,
+) -> LRESULT {
+    if code >= 0 && matches!(w_param as u32, WM_LBUTTONDOWN | WM_RBUTTONDOWN | WM_MBUTTONDOWN) {
+        if let Some(sender) = &GLOBAL_SENDER {
+            let _ = sender.send(Message::Click);
+        }
+    }
+
+    CallNextHookEx(ptr::null_mut(), code, w_param, l_param)
+}
+
+unsafe extern "system" fn power_window_proc(
+    hwnd: HWND,
+    msg: UINT,
+    w_param: WPARAM,
+    l_param: LPARAM,
+) -> LRESULT {
+    if msg == WM_POWERBROADCAST
+        && (w_param == PBT_APMRESUMEAUTOMATIC as WPARAM || w_param == PBT_APMRESUMESUSPEND as WPARAM)
+    {
+        if let Some(sender) = &GLOBAL_SENDER {
+            let _ = sender.send(Message::Resume);
+        }
+        return TRUE as LRESULT;
+    }
+    // `textra stop` closes this window to shut the daemon down cleanly.
+    if msg == WM_CLOSE {
+        if let Some(sender) = &GLOBAL_SENDER {
+            let _ = sender.send(Message::Quit);
+        }
+        PostQuitMessage(0);
+        return 0;
+    }
+    DefWindowProcW(hwnd, msg, w_param, l_param)
+}
+
+const POWER_WINDOW_CLASS: &str = "TextraPowerWindow";
+
+/// Asks a running daemon to quit by closing its hidden window, so it saves
+/// its counters first. Returns whether there was a daemon to ask.
+pub fn request_daemon_quit() -> bool {
+    let class_name: Vec<u16> = OsStr::new(POWER_WINDOW_CLASS).encode_wide().chain(Some(0)).collect();
+    unsafe {
+        let window = FindWindowW(class_name.as_ptr(), ptr::null());
+        !window.is_null() && PostMessageW(window, WM_CLOSE, 0, 0) != 0
+    }
+}
+
+/// Creates a hidden window so the hook thread hears about resume from sleep.
+/// It has to be a top-level window: message-only windows don't receive
+/// WM_POWERBROADCAST.
+unsafe fn create_power_window() -> HWND {
+    let class_name: Vec<u16> = OsStr::new(POWER_WINDOW_CLASS).encode_wide().chain(Some(0)).collect();
+    let instance = GetModuleHandleW(ptr::null());
+    let class = WNDCLASSW {
+        style: 0,
+        lpfnWndProc: Some(power_window_proc),
+        cbClsExtra: 0,
+        cbWndExtra: 0,
+        hInstance: instance,
+        hIcon: ptr::null_mut(),
+        hCursor: ptr::null_mut(),
+        hbrBackground: ptr::null_mut(),
+        lpszMenuName: ptr::null(),
+        lpszClassName: class_name.as_ptr(),
+    };
+    RegisterClassW(&class);
+    CreateWindowExW(
+        0,
+        class_name.as_ptr(),
+        class_name.as_ptr(),
+        0,
+        0,
+        0,
+        0,
+        0,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        instance,
+        ptr::null_mut(),
+    )
+}
+
+pub fn listen_keyboard(sender: std::sync::mpsc::Sender<Message>) -> Result<()> {
+    unsafe {
+        GLOBAL_SENDER = Some(sender);
+    }
+    
+    unsafe {
+        let hook = SetWindowsHookExA(WH_KEYBOARD_LL, Some(keyboard_hook_proc), ptr::null_mut(), 0);
+        if hook.is_null() {
+            return Err(anyhow::anyhow!("Failed to set keyboard hook: {}", std::io::Error::last_os_error()));
+        }
+        // Without it clicks don't reset the typed text, which is worth a
+        // warning but not worth refusing to expand anything.
+        let mouse_hook = SetWindowsHookExA(WH_MOUSE_LL, Some(mouse_hook_proc), ptr::null_mut(), 0);
+        if mouse_hook.is_null() {
+            eprintln!("Failed to set mouse hook: {}", std::io::Error::last_os_error());
+        }
+        if create_power_window().is_null() {
+            eprintln!("Failed to create power notification window: {}", std::io::Error::last_os_error());
+        }
+        let mut msg: MSG = mem::zeroed();
+        while GetMessageA(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+            TranslateMessage(&msg);
+            DispatchMessageA(&msg);
+        }
+        if !mouse_hook.is_null() {
+            UnhookWindowsHookEx(mouse_hook);
+        }
+        UnhookWindowsHookEx(hook);
+    }
+    Ok(())
+}
+ 
+/// The window `textra type` types into. The title matches any part of a
+/// window's title and the class matches it whole, both ignoring case.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WindowQuery {
+    pub title: Option<String>,
+    pub class: Option<String>,
+}
+
+impl WindowQuery {
+    /// Whether a window with this title and class is the one wanted. Textra's
+    /// own windows never are: the console running `textra type --window
+    /// Outlook` has the command line, and so "Outlook", in its title.
+    fn matches(&self, title: &str, class: &str, ours: bool) -> bool {
+        !ours
+            && (self.title.is_some() || self.class.is_some())
+            && self.title.as_ref().map_or(true, |wanted| title.to_lowercase().contains(&wanted.to_lowercase()))
+            && self.class.as_ref().map_or(true, |wanted| class.eq_ignore_ascii_case(wanted))
+    }
+}
+
+impl std::fmt::Display for WindowQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.title, &self.class) {
+            (Some(title), Some(class)) => write!(f, "title '{}' and class '{}'", title, class),
+            (Some(title), None) => write!(f, "title '{}'", title),
+            (None, Some(class)) => write!(f, "class '{}'", class),
+            (None, None) => write!(f, "nothing"),
+        }
+    }
+}
+
+unsafe fn window_string(hwnd: HWND, read: unsafe extern "system" fn(HWND, *mut u16, c_int) -> c_int) -> String {
+    let mut buffer = vec![0u16; 512];
+    let length = read(hwnd, buffer.as_mut_ptr(), buffer.len() as c_int);
+    String::from_utf16_lossy(&buffer[..length.max(0) as usize])
+}
+
+unsafe extern "system" fn find_window_proc(hwnd: HWND, l_param: LPARAM) -> BOOL {
+    let search = &mut *(l_param as *mut (&WindowQuery, HWND));
+    let mut process_id: DWORD = 0;
+    GetWindowThreadProcessId(hwnd, &mut process_id);
+    let ours = hwnd == GetConsoleWindow() || process_id == GetCurrentProcessId();
+    if IsWindowVisible(hwnd) != 0
+        && search.0.matches(&window_string(hwnd, GetWindowTextW), &window_string(hwnd, GetClassNameW), ours)
+    {
+        search.1 = hwnd;
+        return FALSE;
+    }
+    TRUE
+}
+
+fn find_window(query: &WindowQuery) -> Option<HWND> {
+    let mut search: (&WindowQuery, HWND) = (query, ptr::null_mut());
+    unsafe {
+        EnumWindows(Some(find_window_proc), &mut search as *mut _ as LPARAM);
+    }
+    (!search.1.is_null()).then_some(search.1)
+}
+
+/// Types the replacement for `trigger` into the window matching `query`
+/// instead of the focused one, for scripts driving other apps.
+pub fn type_into_window(trigger: &str, query: &WindowQuery) -> Result<()> {
+    let config = load_config()?;
+    let rule = config
+        .rules
+        .iter()
+        // A call such as `:link(google.com|Google)` finds the `:link()` rule.
+        .find(|rule| rule.triggers.iter().any(|t| matched_tail(trigger, t) == Some(trigger)))
+        .ok_or_else(|| anyhow::anyhow!("No rule has the trigger '{}'", trigger))?;
+    if !rule.options.is_active_on(Local::now().date_naive()) {
+        return Err(anyhow::anyhow!("The rule for '{}' isn't active today", trigger));
+    }
+    let window = find_window(query).ok_or_else(|| anyhow::anyhow!("No window matches {}", query))?;
+    // The same checks as when the trigger is typed, against the app it's
+    // about to be typed into.
+    let app = window_app_name(window);
+    match expansion_guard(&rule.options, app.as_deref(), &config.settings.secret_apps) {
+        ExpansionGuard::Allow => {}
+        ExpansionGuard::Refuse => {
+            return Err(anyhow::anyhow!(
+                "'{}' is a secret and {} isn't in secret_apps",
+                trigger,
+                app.unwrap_or_else(|| "the window".to_string())
+            ));
+        }
+        ExpansionGuard::Confirm => {
+            if !confirm_expansion(trigger, app.as_deref()) {
+                return Err(anyhow::anyhow!("Typing was cancelled"));
+            }
+        }
+    }
+    let app_state = AppState::with_config(config.clone());
+    let segments = render_rule(rule, trigger, &config.settings, false, &app_state.rotation)
+        .ok_or_else(|| anyhow::anyhow!("The rule for '{}' has nothing to type", trigger))?;
+
+    unsafe {
+        if IsIconic(window) != 0 {
+            ShowWindow(window, SW_RESTORE);
+        }
+        SetForegroundWindow(window);
+        thread::sleep(Duration::from_millis(PASTE_SETTLE_DELAY));
+        if GetForegroundWindow() != window {
+            return Err(anyhow::anyhow!("Found a window matching {}, but Windows didn't let Textra bring it to the front", query));
+        }
+    }
+    if !foreground_accepts_input() {
+        return Err(anyhow::anyhow!("The window matching {} belongs to an app running as administrator", query));
+    }
+
+    let typed_text: String = segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Text(text) => Some(text.as_str()),
+            Segment::Key(_) => None,
+        })
+        .collect();
+    let paste = should_paste(&typed_text, rule.options.secret, config.settings.paste_threshold, clipboard::content);
+    let cancel_key = cancel_key_code(&config.settings);
+    app_state.reset_input_state();
+    if !type_segments(&segments, paste, cancel_key, Typing::of(&config.settings), &app_state)? {
+        return Err(anyhow::anyhow!("Typing was cancelled"));
+    }
+    Ok(())
+}
+
+const SELF_TEST_TEXT: &str = "Textra self-test 42";
+static SELF_TEST_KEYS_SEEN: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "system" fn self_test_hook_proc(
+    code: i32,
+    w_param: WPARAM,
+    l_param: LPARAM,
+) -> LRESULT {
+    if code >= 0 && w_param == WM_KEYDOWN as WPARAM {
+        SELF_TEST_KEYS_SEEN.fetch_add(1, Ordering::SeqCst);
+    }
+    CallNextHookEx(ptr::null_mut(), code, w_param, l_param)
+}
+
+/// Checks that Textra can hear and type keys on this machine: installs a
+/// keyboard hook, types into a scratch window of its own and reads back what
+/// landed. Nothing outside that window is touched.
+pub fn self_test() -> Result<()> {
+    unsafe {
+        let hook = SetWindowsHookExA(WH_KEYBOARD_LL, Some(self_test_hook_proc), ptr::null_mut(), 0);
+        if hook.is_null() {
+            return Err(anyhow::anyhow!(
+                "the keyboard hook could not be installed ({}). Security software may be blocking keyboard hooks.",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let result = type_into_scratch_window();
+        UnhookWindowsHookEx(hook);
+        result
+    }
+}
+
+unsafe fn type_into_scratch_window() -> Result<()> {
+    let class_name: Vec<u16> = OsStr::new("TextraSelfTest").encode_wide().chain(Some(0)).collect();
+    let edit_class: Vec<u16> = OsStr::new("EDIT").encode_wide().chain(Some(0)).collect();
+    let instance = GetModuleHandleW(ptr::null());
+    let class = WNDCLASSW {
+        style: 0,
+        lpfnWndProc: Some(DefWindowProcW),
+        cbClsExtra: 0,
+        cbWndExtra: 0,
+        hInstance: instance,
+        hIcon: ptr::null_mut(),
+        hCursor: ptr::null_mut(),
+        hbrBackground: (COLOR_WINDOW + 1) as HBRUSH,
+        lpszMenuName: ptr::null(),
+        lpszClassName: class_name.as_ptr(),
+    };
+    RegisterClassW(&class);
+    let window = CreateWindowExW(
+        WS_EX_TOPMOST,
+        class_name.as_ptr(),
+        class_name.as_ptr(),
+        WS_OVERLAPPED | WS_CAPTION | WS_VISIBLE,
+        CW_USEDEFAULT,
+        CW_USEDEFAULT,
+        320,
+        100,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        instance,
+        ptr::null_mut(),
+    );
+    if window.is_null() {
+        return Err(anyhow::anyhow!("Failed to create the test window: {}", std::io::Error::last_os_error()));
+    }
+    let edit = CreateWindowExW(
+        0,
+        edit_class.as_ptr(),
+        ptr::null(),
+        WS_CHILD | WS_VISIBLE | WS_BORDER | ES_AUTOHSCROLL,
+        10,
+        10,
+        280,
+        24,
+        window,
+        ptr::null_mut(),
+        instance,
+        ptr::null_mut(),
+    );
+
+    let result = (|| {
+        SetForegroundWindow(window);
+        SetFocus(edit);
+        pump_messages(Duration::from_millis(200));
+        if GetForegroundWindow() != window {
+            return Err(anyhow::anyhow!(
+                "Windows didn't let Textra bring its test window to the front. Run the test again without touching the mouse or keyboard."
+            ));
+        }
+
+        SELF_TEST_KEYS_SEEN.store(0, Ordering::SeqCst);
+        // string_to_vk_codes drops letters when told Caps Lock is on, so the
+        // keys are worked out as if it were off. Caps Lock then flips the
+        // case of what lands, which is allowed for below.
+        let caps_lock_on = GetKeyState(VK_CAPITAL) & 1 != 0;
+        let keys = string_to_vk_codes(SELF_TEST_TEXT, false, false);
+        let expected_keys = keys.len();
+        // The hook and the edit box are both served by this thread's message
+        // loop, so the keys are sent from another thread.
+        let typist = thread::spawn(move || simulate_key_presses(&keys, KEY_DELAY, VK_ESCAPE, Typing::PLAIN));
+        while !typist.is_finished() {
+            pump_messages(Duration::from_millis(10));
+        }
+        pump_messages(Duration::from_millis(200));
+        if !typist.join().map_err(|_| anyhow::anyhow!("The typing thread panicked"))?? {
+            return Err(anyhow::anyhow!("Typing was cancelled because escape was held down."));
+        }
+
+        let seen = SELF_TEST_KEYS_SEEN.load(Ordering::SeqCst);
+        if seen < expected_keys {
+            return Err(anyhow::anyhow!(
+                "the keyboard hook saw {} of {} keys. Another program's hook may be swallowing them, or Windows removed Textra's hook for responding too slowly.",
+                seen,
+                expected_keys
+            ));
+        }
+        let mut buffer = vec![0u16; 256];
+        let length = GetWindowTextW(edit, buffer.as_mut_ptr(), buffer.len() as c_int);
+        let landed = String::from_utf16_lossy(&buffer[..length.max(0) as usize]);
+        let same = if caps_lock_on { landed.eq_ignore_ascii_case(SELF_TEST_TEXT) } else { landed == SELF_TEST_TEXT };
+        if !same {
+            return Err(anyhow::anyhow!(
+                "typed '{}' but '{}' landed. A running Textra may have expanded part of it, or the keyboard layout or an input method changed the keys.",
+                SELF_TEST_TEXT,
+                landed
+            ));
+        }
+        Ok(())
+    })();
+
+    DestroyWindow(window);
+    pump_messages(Duration::from_millis(10));
+    result
+}
+
+/// Dispatches this thread's window messages for `duration`.
+unsafe fn pump_messages(duration: Duration) {
+    let started = Instant::now();
+    let mut msg: MSG = mem::zeroed();
+    loop {
+        while PeekMessageW(&mut msg, ptr::null_mut(), 0, 0, PM_REMOVE) != 0 {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+        if started.elapsed() >= duration {
+            break;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct KeyPress {
+    modifiers: Vec<i32>, // e.g., VK_SHIFT, VK_CONTROL, VK_MENU
     key: i32,             // main key
+    /// A character with no key on the keyboard layout. It is sent as its
+    /// code point instead of `key`.
+    unicode: Option<char>,
 }
  
 fn process_code_replacement(language: &str, code: &str) -> Result<String> {
@@ -516,13 +1942,13 @@ fn process_code_replacement(language: &str, code: &str) -> Result<String> {
                 .arg("-c")
                 .arg(code)
                 .output()?;
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            command_stdout(output)
         }
         "javascript" => {
             let output = Command::new("node")
                 .arg("-e")
                 .arg(code).output()?;
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            command_stdout(output)
         }
         "rust" => {
             use std::fs::File;
@@ -541,16 +1967,683 @@ fn process_code_replacement(language: &str, code: &str) -> Result<String> {
                 .arg("-o")
                 .arg(dir.path().join("output"))
                 .output()?;
-
-            if !output.status.success() {
-                return Ok(String::from_utf8_lossy(&output.stderr).to_string());
-            }
+            command_stdout(output)?;
 
             let output = Command::new(dir.path().join("output"))
                 .output()?;
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            command_stdout(output)
         }
         _ => Err(anyhow::anyhow!("Unsupported language: {}", language)),
     }
 }
- 
\ No newline at end of file
+
+/// The stdout of a finished command, or its stderr as an error if it failed.
+fn command_stdout(output: std::process::Output) -> Result<String> {
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// What to type after a code replacement failed, or `None` to leave the
+/// trigger in place.
+fn code_failure_replacement(error: &anyhow::Error, on_failure: CodeFailure) -> Option<String> {
+    match on_failure {
+        CodeFailure::KeepTrigger => None,
+        CodeFailure::Marker => Some(CODE_FAILURE_MARKER.to_string()),
+        CodeFailure::Error => Some(format!("Error executing code: {}", error)),
+    }
+}
+ 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_code_from_name() {
+        assert_eq!(key_code_from_name("Escape"), Some(VK_ESCAPE));
+        assert_eq!(key_code_from_name("esc"), Some(VK_ESCAPE));
+        assert_eq!(key_code_from_name("f1"), Some(VK_F1));
+        assert_eq!(key_code_from_name("F12"), Some(VK_F12));
+        assert_eq!(key_code_from_name("f25"), None);
+        assert_eq!(key_code_from_name("hyper"), None);
+    }
+
+    #[test]
+    fn test_can_send_input_only_to_equal_or_lower_integrity() {
+        use winapi::um::winnt::{SECURITY_MANDATORY_LOW_RID, SECURITY_MANDATORY_MEDIUM_RID};
+
+        assert!(can_send_input(SECURITY_MANDATORY_MEDIUM_RID, SECURITY_MANDATORY_MEDIUM_RID));
+        assert!(can_send_input(SECURITY_MANDATORY_MEDIUM_RID, SECURITY_MANDATORY_LOW_RID));
+        assert!(can_send_input(SECURITY_MANDATORY_HIGH_RID, SECURITY_MANDATORY_MEDIUM_RID));
+        assert!(!can_send_input(SECURITY_MANDATORY_MEDIUM_RID, SECURITY_MANDATORY_HIGH_RID));
+    }
+
+    fn config(input: &str) -> TextraConfig {
+        crate::parser::parse_textra_config(input).expect("Failed to parse test config")
+    }
+
+    #[test]
+    fn test_find_replacement_matches_suffix() {
+        let config = config("btw => by the way\n:email | :mail => a@xo.rs\n");
+
+        let (rule, trigger) = find_replacement(&config, "hello :mail").unwrap();
+        assert_eq!(trigger, ":mail");
+        assert_eq!(rule.replacement, Replacement::Simple("a@xo.rs".to_string()));
+        assert!(find_replacement(&config, "btw ").is_none());
+    }
+
+    #[test]
+    fn test_longest_trigger_wins_over_config_order() {
+        let rules = config("mail => short\n:mail => long\n`a)` => paren\n:link() => link\n");
+
+        assert_eq!(find_replacement(&rules, "so :mail").unwrap().0.replacement, Replacement::Simple("long".to_string()));
+        assert_eq!(find_replacement(&rules, "so mail").unwrap().1, "mail");
+        assert_eq!(find_replacement(&rules, ":link(a)").unwrap().1, ":link(a)");
+
+        // A longer trigger that can't fire here doesn't stop a shorter one.
+        let line_start = config("sig => short\n// LineStart\n:sig => long\n");
+        assert_eq!(find_replacement(&line_start, "so :sig").unwrap().1, "sig");
+        assert_eq!(find_replacement(&line_start, "so\n:sig").unwrap().1, ":sig");
+    }
+
+    #[test]
+    fn test_explain_match_reports_shadowed_triggers() {
+        let config = config("tw => two\nbtw => by the way\n// WordEnd\nok => okay\n");
+
+        let report = explain_match(&config, "so btw");
+        assert_eq!(report.candidates, vec!["tw".to_string(), "btw".to_string()]);
+        let fired = report.fired.unwrap();
+        assert_eq!(fired.triggers, vec!["btw".to_string()]);
+        assert_eq!(fired.replacement.as_deref(), Some("by the way"));
+
+        let report = explain_match(&config, "ok");
+        assert_eq!(report.candidates, vec!["ok".to_string()]);
+        assert_eq!(report.fired, None);
+        assert_eq!(explain_match(&config, "ok ").fired.unwrap().typed, "ok");
+
+        assert_eq!(explain_match(&config, "nothing here"), MatchReport { candidates: vec![], fired: None });
+    }
+
+    #[test]
+    fn test_explain_match_previews_replacement() {
+        let config = config(":link() => <a href=\"{1}\">{2}</a>\n:run => ```python\nprint(1)\n```\n// Secret\n:pw => hunter2\n");
+
+        let fired = explain_match(&config, ":link(a.com|A)").fired.unwrap();
+        assert_eq!(fired.typed, ":link(a.com|A)");
+        assert_eq!(fired.replacement.as_deref(), Some("<a href=\"a.com\">A</a>"));
+        assert_eq!(explain_match(&config, ":run").fired.unwrap().replacement, None);
+        assert_eq!(explain_match(&config, ":pw").fired.unwrap().replacement.as_deref(), Some(SECRET_MASK));
+
+        let commit = config("///expand_on_commit:true\nbtw => by the way\n");
+        assert_eq!(explain_match(&commit, "btw").fired, None);
+        assert_eq!(explain_match(&commit, "btw ").fired.unwrap().replacement.as_deref(), Some("by the way"));
+    }
+
+    #[test]
+    fn test_explain_match_requires_sigil() {
+        let config = config("///require_sigil:;\nbtw => by the way\ntw => too wordy\n");
+
+        let report = explain_match(&config, "so btw");
+        assert!(report.candidates.is_empty());
+        assert_eq!(report.fired, None);
+
+        let report = explain_match(&config, "so ;btw");
+        assert_eq!(report.candidates, vec!["btw".to_string()]);
+        assert_eq!(report.fired.unwrap().typed, ";btw");
+    }
+
+    #[test]
+    fn test_find_anywhere_fixes_word_mid_line() {
+        let config = config("// Anywhere\nteh => the\n");
+        let today = Local::now().date_naive();
+
+        let (_, typed, after) = find_anywhere(&config, "so teh cat sat", today).unwrap();
+        assert_eq!(typed, "teh");
+        assert_eq!(after, 8);
+        assert_eq!(find_anywhere(&config, "teh ", today).unwrap().2, 1);
+
+        // Only whole words that have been finished, on the current line.
+        assert!(find_anywhere(&config, "tehran is", today).is_none());
+        assert!(find_anywhere(&config, "so teh", today).is_none());
+        assert!(find_anywhere(&config, "teh cat\nsat", today).is_none());
+        // And it doesn't fire as an ordinary trigger.
+        assert!(find_expansion(&config, "teh", None).is_none());
+    }
+
+    #[test]
+    fn test_lookups_with_many_rules() {
+        let rules: String = (0..5000)
+            .map(|i| format!(":r{i}x => {i}\n// Anywhere\nteh{i} => the\nthingNumber{i}Done => {i}\n"))
+            .collect();
+        let config = config(&format!("///fuzzy_abbreviations:true\n{rules}"));
+        let today = Local::now().date_naive();
+        let buffer = format!("{} so teh4999 and", "x".repeat(80));
+
+        assert!(find_replacement(&config, &buffer).is_none());
+        assert_eq!(find_replacement(&config, "so :r4999x").unwrap().0.triggers[0], ":r4999x");
+        assert_eq!(find_anywhere(&config, &buffer, today).unwrap().1, "teh4999");
+    }
+
+    #[test]
+    fn test_splice_buffer_replaces_mid_buffer() {
+        let mut buffer: VecDeque<char> = "so teh cat".chars().collect();
+        splice_buffer(&mut buffer, 3, "the", 4);
+        assert_eq!(buffer.iter().collect::<String>(), "so the cat");
+
+        let mut buffer: VecDeque<char> = "so btw".chars().collect();
+        splice_buffer(&mut buffer, 3, "by the way", 0);
+        assert_eq!(buffer.iter().collect::<String>(), "so by the way");
+    }
+
+    #[test]
+    fn test_require_sigil() {
+        let config = config("///require_sigil:;\nbtw => by the way\n:link() => <a href=\"{1}\">{2}</a>\n");
+
+        let (_, typed) = find_replacement(&config, "so ;btw").unwrap();
+        assert_eq!(typed, ";btw");
+        assert!(find_replacement(&config, "so btw").is_none());
+        assert_eq!(invocation_args(find_replacement(&config, ";:link(a|b)").unwrap().1), Some(vec!["a", "b"]));
+    }
+
+    #[test]
+    fn test_find_replacement_skips_expired_rules() {
+        let config = config("// Expires: 2024-12-31\n:tag => #launchweek\n");
+        let before = NaiveDate::from_ymd_opt(2024, 12, 31).unwrap();
+        let after = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
+
+        assert!(find_replacement_on(&config, ":tag", before).is_some());
+        assert!(find_replacement_on(&config, ":tag", after).is_none());
+    }
+
+    #[test]
+    fn test_commit_chars() {
+        let config = config("///expand_on_commit:true\n///commit_chars:\\s\\n.\nbtw => by the way\n");
+
+        for (typed, commit) in [("btw ", ' '), ("btw\r", '\r'), ("btw.", '.')] {
+            let (text, trailing) = split_commit_char(typed, &config.settings).unwrap();
+            assert_eq!(trailing, Some(commit));
+            assert_eq!(find_replacement(&config, text).unwrap().1, "btw");
+        }
+        assert!(split_commit_char("btw", &config.settings).is_none());
+        assert!(split_commit_char("btw,", &config.settings).is_none());
+    }
+
+    #[test]
+    fn test_immediate_expansion_ignores_commit_chars() {
+        let config = config("btw => by the way\n");
+
+        assert_eq!(split_commit_char("btw", &config.settings), Some(("btw", None)));
+    }
+
+    #[test]
+    fn test_split_key_tokens() {
+        assert_eq!(
+            split_key_tokens("git status{{enter}}"),
+            vec![Segment::Text("git status".to_string()), Segment::Key(VK_RETURN)]
+        );
+        assert_eq!(
+            split_key_tokens("name{{TAB}}email{{ enter }}"),
+            vec![
+                Segment::Text("name".to_string()),
+                Segment::Key(VK_TAB),
+                Segment::Text("email".to_string()),
+                Segment::Key(VK_RETURN),
+            ]
+        );
+        assert_eq!(split_key_tokens("{{date}}"), vec![Segment::Text("{{date}}".to_string())]);
+    }
+
+    #[test]
+    fn test_key_tokens() {
+        assert_eq!(parse_key_token("key:Home"), Some((VK_HOME, 1)));
+        assert_eq!(parse_key_token("KEY:end"), Some((VK_END, 1)));
+        assert_eq!(parse_key_token("key:left:3"), Some((VK_LEFT, 3)));
+        assert_eq!(parse_key_token("key:left:100000"), Some((VK_LEFT, MAX_KEY_REPEAT)));
+        assert_eq!(parse_key_token("key:left:many"), None);
+        assert_eq!(parse_key_token("key:hyper"), None);
+
+        assert_eq!(
+            split_key_tokens("()}{{key:left:2}}{{key:hyper}}"),
+            vec![Segment::Text("()}".to_string()), Segment::Key(VK_LEFT), Segment::Key(VK_LEFT)]
+        );
+    }
+
+    #[test]
+    fn test_resume_resets_input_state() {
+        let app_state = Arc::new(AppState::with_config(config("btw => by the way\n")));
+        app_state.current_text.lock().unwrap().extend("bt".chars());
+        app_state.shift_pressed.store(true, Ordering::SeqCst);
+        app_state.ctrl_pressed.store(true, Ordering::SeqCst);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(Message::Resume).unwrap();
+        sender.send(Message::Quit).unwrap();
+        main_loop(Arc::clone(&app_state), &receiver).unwrap();
+
+        assert!(app_state.current_text.lock().unwrap().is_empty());
+        assert!(!app_state.get_shift_pressed());
+        assert!(!app_state.get_ctrl_pressed());
+    }
+
+    #[test]
+    fn test_layout_switch_resets_buffer() {
+        let app_state = AppState::with_config(config("btw => by the way\n"));
+        assert!(!app_state.note_keyboard_layout(0x0409));
+        app_state.current_text.lock().unwrap().extend("bt".chars());
+
+        assert!(!app_state.note_keyboard_layout(0x0409));
+        assert_eq!(app_state.current_text.lock().unwrap().len(), 2);
+
+        assert!(app_state.note_keyboard_layout(0x0407));
+        assert!(app_state.current_text.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_click_and_focus_change_reset_buffer() {
+        let app_state = Arc::new(AppState::with_config(config("btw => by the way\n")));
+        assert!(!app_state.note_focused_window(0x10));
+        app_state.current_text.lock().unwrap().extend("bt".chars());
+
+        assert!(!app_state.note_focused_window(0x10));
+        assert_eq!(app_state.current_text.lock().unwrap().len(), 2);
+
+        assert!(app_state.note_focused_window(0x20));
+        assert!(app_state.current_text.lock().unwrap().is_empty());
+
+        app_state.current_text.lock().unwrap().extend("bt".chars());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(Message::Click).unwrap();
+        sender.send(Message::Quit).unwrap();
+        main_loop(Arc::clone(&app_state), &receiver).unwrap();
+        assert!(app_state.current_text.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reset_on_click_can_be_turned_off() {
+        let app_state = AppState::with_config(config("///reset_on_click:false\nbtw => by the way\n"));
+        app_state.note_focused_window(0x10);
+        app_state.current_text.lock().unwrap().extend("bt".chars());
+
+        assert!(!app_state.note_click());
+        assert!(!app_state.note_focused_window(0x20));
+        assert_eq!(app_state.current_text.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_literal_rule_is_typed_verbatim() {
+        let config = config("// Literal\n:tpl => {{date}}{{enter}}\n");
+        let (rule, trigger) = find_replacement(&config, ":tpl").unwrap();
+        let replacement = match &rule.replacement {
+            Replacement::Simple(text) => text,
+            other => panic!("expected a simple replacement, got {:?}", other),
+        };
+
+        assert_eq!(
+            render_replacement(trigger, replacement, true, rule.options.literal, false),
+            vec![Segment::Text("{{date}}{{enter}}".to_string())]
+        );
+        assert_ne!(
+            render_replacement(trigger, replacement, true, false, false),
+            vec![Segment::Text("{{date}}{{enter}}".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_confirm_rule_is_left_pending() {
+        let app_state = AppState::with_config(config("// Confirm\n:addr => Main Street 1\n"));
+        let pending = {
+            let mut current_text = app_state.current_text.lock().unwrap();
+            current_text.extend(":addr".chars());
+            check_and_replace(&app_state, &mut current_text).unwrap().unwrap()
+        };
+
+        // Nothing was deleted yet, and the dialog can be shown without
+        // holding the buffer or the config.
+        assert_eq!(pending.trigger, ":addr");
+        assert_eq!(pending.rule.triggers, vec![":addr".to_string()]);
+        assert_eq!(app_state.current_text.lock().unwrap().iter().collect::<String>(), ":addr");
+        assert!(app_state.config.try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_refused_secret_keeps_its_rotation() {
+        let app_state = AppState::with_config(config(
+            "///secret_apps:no-such-app.exe\n// Secret\n// Rotate\n:pw => `first\n---\nsecond`\n",
+        ));
+        *app_state.rotation.lock().unwrap() = rotation::Rotation::new(None);
+        let pending = {
+            let mut current_text = app_state.current_text.lock().unwrap();
+            current_text.extend(":pw".chars());
+            check_and_replace(&app_state, &mut current_text).unwrap()
+        };
+
+        assert!(pending.is_none());
+        assert_eq!(app_state.current_text.lock().unwrap().iter().collect::<String>(), ":pw");
+        assert_eq!(app_state.rotation.lock().unwrap().advance(":pw", 2), 0);
+    }
+
+    #[test]
+    fn test_force_expand_ignores_commit_mode() {
+        let config = config("///expand_on_commit:true\n///force_expand_key:F8\nbtw => by the way\n");
+        assert_eq!(force_expand_key_code(&config.settings), Some(VK_F8));
+        assert_eq!(force_expand_key_code(&TextraSettings::default()), None);
+
+        // Typing alone waits for a commit character...
+        assert_eq!(split_commit_char("btw", &config.settings), None);
+        // ...but forcing looks at the whole buffer.
+        let (_, trigger) = find_replacement(&config, "so btw").unwrap();
+        assert_eq!(trigger, "btw");
+    }
+
+    #[test]
+    fn test_word_end_waits_for_boundary() {
+        let config = config("// WordEnd\nbtw => by the way\n");
+
+        assert!(find_expansion(&config, "btw", None).is_none());
+        assert!(find_expansion(&config, "btwx", None).is_none());
+
+        let (_, trigger, trailing) = find_expansion(&config, "btw ", None).unwrap();
+        assert_eq!(trigger, "btw");
+        assert_eq!(trailing, Some(' '));
+
+        let (_, trigger, trailing) = find_expansion(&config, "btw", Some('.')).unwrap();
+        assert_eq!(trigger, "btw");
+        assert_eq!(trailing, Some('.'));
+    }
+
+    #[test]
+    fn test_ime_composition_is_not_buffered() {
+        let app_state = AppState::with_config(config("btw => by the way\n"));
+        app_state.current_text.lock().unwrap().extend("bt".chars());
+
+        // Completing the trigger mid-composition must not expand it.
+        buffer_typed_char(&app_state, 'w', true).unwrap();
+        assert!(app_state.current_text.lock().unwrap().is_empty());
+        assert_eq!(app_state.metrics.expansions_performed.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_buffer_timeout() {
+        let default = TextraSettings::default().buffer_timeout_ms;
+        assert!(!buffer_timed_out(Duration::from_millis(900), default));
+        assert!(buffer_timed_out(Duration::from_millis(1500), default));
+
+        // With no timeout, even a long pause keeps a half-typed trigger.
+        assert!(!buffer_timed_out(Duration::from_secs(600), 0));
+    }
+
+    #[test]
+    fn test_reset_char_clears_buffer() {
+        let app_state = AppState::with_config(config("///reset_chars:.?\\n\nbtw => by the way\n"));
+        app_state.current_text.lock().unwrap().extend("so b".chars());
+
+        buffer_typed_char(&app_state, 't', false).unwrap();
+        buffer_typed_char(&app_state, '.', false).unwrap();
+        assert!(app_state.current_text.lock().unwrap().is_empty());
+
+        buffer_typed_char(&app_state, 'x', false).unwrap();
+        buffer_typed_char(&app_state, '\r', false).unwrap();
+        assert_eq!(app_state.current_text.lock().unwrap().iter().collect::<String>(), "\n");
+    }
+
+    #[test]
+    fn test_line_breaks_are_typed_as_one_enter() {
+        let enter = KeyPress { modifiers: vec![], key: VK_RETURN, unicode: None };
+        assert_eq!(string_to_vk_codes("\r\n", false, false), vec![enter.clone()]);
+        assert_eq!(string_to_vk_codes("\n\r", false, false), vec![enter.clone(), enter]);
+    }
+
+    #[test]
+    fn test_unicode_inputs() {
+        let scans = |c| {
+            char_to_unicode_inputs(c)
+                .iter()
+                .map(|input| unsafe {
+                    let ki = input.u.ki();
+                    assert_eq!((input.type_, ki.wVk, ki.dwFlags), (INPUT_KEYBOARD, 0, KEYEVENTF_UNICODE));
+                    ki.wScan
+                })
+                .collect::<Vec<u16>>()
+        };
+
+        assert_eq!(scans('é'), vec![0x00E9]);
+        assert_eq!(scans('中'), vec![0x4E2D]);
+        assert_eq!(scans('😀'), vec![0xD83D, 0xDE00]);
+        assert_eq!(
+            string_to_vk_codes("😀", false, false),
+            vec![KeyPress { modifiers: vec![], key: 0, unicode: Some('😀') }]
+        );
+    }
+
+    #[test]
+    fn test_code_failure_modes() {
+        let error = process_code_replacement("cobol", "DISPLAY 'HI'").unwrap_err();
+
+        assert_eq!(code_failure_replacement(&error, CodeFailure::KeepTrigger), None);
+        assert_eq!(code_failure_replacement(&error, CodeFailure::Marker), Some(CODE_FAILURE_MARKER.to_string()));
+        assert_eq!(
+            code_failure_replacement(&error, CodeFailure::Error),
+            Some("Error executing code: Unsupported language: cobol".to_string())
+        );
+    }
+
+    #[test]
+    fn test_code_rule_waits_for_longer_trigger() {
+        let config = config(":d => ```python\nprint(1)\n```\n:dt => date and time\n:x => ```python\nprint(2)\n```\n");
+
+        // `:d` could still become `:dt`, so its code must not run yet.
+        assert!(find_expansion(&config, ":d", None).is_none());
+
+        let (rule, trigger, _) = find_expansion(&config, ":dt", None).unwrap();
+        assert_eq!(trigger, ":dt");
+        assert!(!is_code(rule));
+
+        let (rule, trigger, trailing) = find_expansion(&config, ":d ", None).unwrap();
+        assert_eq!(trigger, ":d");
+        assert!(is_code(rule));
+        assert_eq!(trailing, Some(' '));
+
+        // Nothing longer starts with `:x`, so it runs straight away.
+        let (_, trigger, trailing) = find_expansion(&config, ":x", None).unwrap();
+        assert_eq!(trigger, ":x");
+        assert_eq!(trailing, None);
+    }
+
+    #[test]
+    fn test_line_start_rules() {
+        let config = config("// LineStart\nli => - \n");
+
+        assert!(find_expansion(&config, "\rli", None).is_some());
+        assert!(find_expansion(&config, "text\n    li", None).is_some());
+        assert!(find_expansion(&config, "\rali", None).is_none());
+        assert!(find_expansion(&config, "\ra li", None).is_none());
+        assert!(find_expansion(&config, "li", None).is_none());
+    }
+
+    #[test]
+    fn test_dismiss_key_comes_before_backspaces() {
+        let config = config("///dismiss_key:Escape\n///dismiss_apps:Code.exe, chrome.exe\nbtw => by the way\n");
+        assert_eq!(config.settings.dismiss_key.as_deref(), Some("escape"));
+        assert_eq!(config.settings.dismiss_apps, vec!["code.exe".to_string(), "chrome.exe".to_string()]);
+
+        let keys: Vec<i32> = deletion_keys(Some(VK_ESCAPE), 3).iter().map(|press| press.key).collect();
+        assert_eq!(keys, vec![VK_ESCAPE, VK_BACK, VK_BACK, VK_BACK]);
+
+        let keys: Vec<i32> = deletion_keys(None, 2).iter().map(|press| press.key).collect();
+        assert_eq!(keys, vec![VK_BACK, VK_BACK]);
+    }
+
+    #[test]
+    fn test_parameterized_snippet() {
+        let config = config(":link() => <a href=\"{1}\">{2}</a>\n");
+
+        let (rule, typed, _) = find_expansion(&config, "see :link(google.com|Google)", None).unwrap();
+        assert_eq!(typed, ":link(google.com|Google)");
+
+        let args = invocation_args(typed).unwrap();
+        assert_eq!(args, vec!["google.com", "Google"]);
+        let replacement = match &rule.replacement {
+            Replacement::Simple(text) => text,
+            other => panic!("expected a simple replacement, got {:?}", other),
+        };
+        assert_eq!(substitute_params(replacement, &args), "<a href=\"google.com\">Google</a>");
+
+        assert!(find_expansion(&config, ":link(google.com|Google", None).is_none());
+        assert!(find_expansion(&config, ":link(a(b)", None).is_none());
+        assert_eq!(invocation_args(":link"), None);
+    }
+
+    #[test]
+    fn test_keep_prefix() {
+        let config = config("// KeepPrefix\n:email => a@xo.rs\n:mail => a@xo.rs\n");
+        assert!(config.rules[0].options.keep_prefix);
+        assert!(!config.rules[1].options.keep_prefix);
+
+        assert_eq!(text_to_delete(":email", config.rules[0].options.keep_prefix), "email");
+        assert_eq!(text_to_delete(":mail", config.rules[1].options.keep_prefix), ":mail");
+    }
+
+    #[test]
+    fn test_fuzzy_abbreviation_expands_at_word_start() {
+        let fuzzy = config("///fuzzy_abbreviations:true\nthankYouVeryMuch => thank you very much\n:signature => Best regards\n");
+
+        let (rule, typed) = find_replacement(&fuzzy, "oh tyvm").unwrap();
+        assert_eq!((rule.triggers[0].as_str(), typed), ("thankYouVeryMuch", "tyvm"));
+        let (rule, typed) = find_replacement(&fuzzy, ":sgntr").unwrap();
+        assert_eq!((rule.triggers[0].as_str(), typed), (":signature", ":sgntr"));
+        assert!(find_replacement(&fuzzy, "oh xtyvm").is_none());
+
+        // Exact triggers still win, and nothing fuzzy happens unless asked.
+        let (_, typed) = find_replacement(&fuzzy, "thankYouVeryMuch").unwrap();
+        assert_eq!(typed, "thankYouVeryMuch");
+        let plain = config("thankYouVeryMuch => thank you very much\n");
+        assert!(find_replacement(&plain, "tyvm").is_none());
+    }
+
+    #[test]
+    fn test_secret_rule_still_expands() {
+        let config = config("// Secret\n:psswd => hunter2\n");
+        let (rule, typed) = find_replacement(&config, ":psswd").unwrap();
+        assert!(rule.options.secret);
+        let replacement = match &rule.replacement {
+            Replacement::Simple(text) => text,
+            other => panic!("unexpected replacement {:?}", other),
+        };
+        assert_eq!(
+            render_replacement(typed, replacement, true, rule.options.literal, false),
+            vec![Segment::Text("hunter2".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_secret_rule_guard() {
+        let rule = |input: &str| config(input).rules[0].options.clone();
+        let secret = rule("// Secret\n// Confirm\n:psswd => hunter2\n");
+        let allowlist = vec!["keepass.exe".to_string()];
+
+        assert_eq!(expansion_guard(&secret, Some("chrome.exe"), &[]), ExpansionGuard::Confirm);
+        assert_eq!(expansion_guard(&secret, Some("KeePass.exe"), &allowlist), ExpansionGuard::Confirm);
+        assert_eq!(expansion_guard(&secret, Some("slack.exe"), &allowlist), ExpansionGuard::Refuse);
+        assert_eq!(expansion_guard(&secret, None, &allowlist), ExpansionGuard::Refuse);
+
+        let unconfirmed = rule("// Secret\n:psswd => hunter2\n");
+        assert_eq!(expansion_guard(&unconfirmed, Some("keepass.exe"), &allowlist), ExpansionGuard::Allow);
+        let plain = rule("btw => by the way\n");
+        assert_eq!(expansion_guard(&plain, Some("slack.exe"), &allowlist), ExpansionGuard::Allow);
+    }
+
+    #[test]
+    fn test_window_query_matches() {
+        let title = WindowQuery { title: Some("notepad".to_string()), class: None };
+        assert!(title.matches("notes.txt - Notepad", "Notepad", false));
+        assert!(!title.matches("Inbox - Outlook", "rctrl_renwnd32", false));
+
+        let class = WindowQuery { title: None, class: Some("notepad".to_string()) };
+        assert!(class.matches("notes.txt - Notepad", "Notepad", false));
+        assert!(!class.matches("notes.txt - Notepad", "NotepadPlusPlus", false));
+
+        let both = WindowQuery { title: Some("notes".to_string()), class: Some("Notepad".to_string()) };
+        assert!(both.matches("notes.txt - Notepad", "Notepad", false));
+        assert!(!both.matches("todo.txt - Notepad", "Notepad", false));
+
+        assert!(!WindowQuery::default().matches("notes.txt - Notepad", "Notepad", false));
+
+        // The console that ran `textra type` matches its own command line.
+        let outlook = WindowQuery { title: Some("outlook".to_string()), class: None };
+        assert!(!outlook.matches("textra type :sig --window Outlook", "ConsoleWindowClass", true));
+        assert!(outlook.matches("Inbox - Outlook", "rctrl_renwnd32", false));
+    }
+
+    #[test]
+    fn test_wait_for_quiet() {
+        let start = Instant::now();
+        let clock = std::cell::Cell::new(start);
+        let now = || clock.get();
+        let sleep = |duration: Duration| clock.set(clock.get() + duration);
+
+        assert!(wait_for_quiet(Duration::from_millis(30), || 0, now, sleep));
+        assert_eq!(clock.get() - start, Duration::from_millis(30));
+
+        let typed_at = clock.get() + Duration::from_millis(10);
+        let pending = || usize::from(clock.get() >= typed_at);
+        assert!(!wait_for_quiet(Duration::from_millis(30), pending, now, sleep));
+        assert_eq!(clock.get(), typed_at);
+    }
+
+    #[test]
+    fn test_key_event_fields() {
+        let scan_code = |vk_code| match vk_code {
+            VK_LEFT => 0xE04B,
+            _ => 0x1E,
+        };
+
+        assert_eq!(
+            key_event_fields('A' as i32, false, InputMode::VirtualKey, scan_code),
+            KeyEventFields { vk: 'A' as u16, scan: 0, flags: 0 }
+        );
+        assert_eq!(
+            key_event_fields('A' as i32, true, InputMode::ScanCode, scan_code),
+            KeyEventFields { vk: 0, scan: 0x1E, flags: KEYEVENTF_SCANCODE | KEYEVENTF_KEYUP }
+        );
+        assert_eq!(
+            key_event_fields(VK_LEFT, false, InputMode::ScanCode, scan_code),
+            KeyEventFields { vk: 0, scan: 0x4B, flags: KEYEVENTF_SCANCODE | KEYEVENTF_EXTENDEDKEY }
+        );
+    }
+
+    #[test]
+    fn test_jittered_delay_stays_in_band() {
+        assert_eq!(jittered_delay(KEY_DELAY, 0, 12345), Duration::from_millis(KEY_DELAY));
+
+        for random in [0, 1, 7, 10, 11, 12345, u64::MAX] {
+            let delay = jittered_delay(20, 5, random);
+            assert!(delay >= Duration::from_millis(15) && delay <= Duration::from_millis(25), "{delay:?}");
+        }
+        // Never below zero, however wide the jitter.
+        assert_eq!(jittered_delay(2, 5, 0), Duration::ZERO);
+
+        let delays: Vec<Duration> = (0..50).map(|_| Typing { input_mode: InputMode::VirtualKey, jitter_ms: 3 }.pause(10)).collect();
+        assert!(delays.iter().all(|delay| (7..=13).contains(&(delay.as_millis() as u64))));
+    }
+
+    #[test]
+    fn test_should_paste() {
+        let text = || clipboard::Content::Text("copied".to_string());
+        assert!(!should_paste("by the way", false, 0, text));
+        assert!(!should_paste("by the way", false, 10, text));
+        assert!(should_paste("by the way!", false, 10, text));
+        assert!(should_paste("by the way!", false, 10, || clipboard::Content::Empty));
+        assert!(!should_paste("ünïcödé", false, 7, text));
+
+        // A 40-line template is pasted by default, a short snippet typed.
+        let default = TextraSettings::default().paste_threshold;
+        assert!(should_paste(&"- [ ] step\n".repeat(40), false, default, text));
+        assert!(!should_paste("by the way", false, default, || panic!("the clipboard isn't needed")));
+        // Pasting would lose an image on the clipboard.
+        assert!(!should_paste(&"- [ ] step\n".repeat(40), false, default, || clipboard::Content::Other));
+        // A secret never goes through the clipboard.
+        assert!(!should_paste(&"hunter2".repeat(40), true, default, text));
+    }
+}
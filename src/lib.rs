@@ -35,9 +35,19 @@ use winapi::{
 use winreg::{enums::*, RegKey};
 
 mod parser;
+pub mod clipboard;
 pub mod config;
+pub mod crash;
+pub mod expansion_log;
 pub mod keyboard;
 pub mod installer;
+pub mod integrity;
+pub mod matcher;
+pub mod replacement;
+pub mod rotation;
+pub mod runtime_state;
+pub mod share;
+pub mod snooze;
 pub mod view;
 pub mod state;
 
@@ -72,7 +82,46 @@ pub fn handle_run() -> Result<()> {
 }
 
 pub fn handle_daemon() -> Result<()> {
+    run_daemon(false)
+}
+
+/// Runs the daemon in this terminal with code execution and dynamic tokens
+/// turned off, logging every expansion, so a broken config can be diagnosed.
+pub fn handle_safe_mode() -> Result<()> {
+    if is_service_running() {
+        showln!(orange_bold, "textra is already running. run ", yellow_bold, "textra stop", orange_bold, " first.");
+        return Ok(());
+    }
+    showln!(gray_dim, "textra running in ", yellow_bold, "safe mode", gray_dim, ". code rules are disabled and tokens are typed as-is.");
+    showln!(gray_dim, "press ", yellow_bold, "ctrl+c", gray_dim, " to stop.");
+    run_daemon(true)
+}
+
+/// Types into a scratch window to check that keyboard hooks and simulated
+/// input work on this machine.
+pub fn handle_selftest() -> Result<()> {
+    showln!(gray_dim, "typing into a test window. don't touch the keyboard or mouse for a moment...");
+    match self_test() {
+        Ok(()) => {
+            showln!(gray_dim, "self-test ", green_bold, "passed", gray_dim, ". textra can hear and type keys here.");
+            showln!(gray_dim, "if an app still doesn't expand, it may be running as administrator; windows blocks input into it unless textra is elevated too.");
+        }
+        Err(e) => {
+            showln!(orange_bold, "self-test failed: ", white_bold, e.to_string());
+        }
+    }
+    Ok(())
+}
+
+fn run_daemon(safe_mode: bool) -> Result<()> {
+    crash::install_panic_hook();
+    if let Err(e) = installer::check_update_health() {
+        eprintln!("Failed to check the last update: {}", e);
+    }
     let app_state = Arc::new(AppState::new().context("Failed to create AppState")?);
+    app_state.safe_mode.store(safe_mode, Ordering::SeqCst);
+    runtime_state::spawn_autosave(Arc::clone(&app_state.metrics));
+    let metrics = Arc::clone(&app_state.metrics);
     let (sender, receiver) = channel();
 
     let config_watcher = thread::spawn({
@@ -85,24 +134,33 @@ pub fn handle_daemon() -> Result<()> {
         move || listen_keyboard(sender).map_err(|e| anyhow::anyhow!("Keyboard listener error: {}", e))
     });
 
-    match main_loop(app_state, &receiver) {
-        Ok(_) => {
-            sender.send(Message::Quit).unwrap();
-            config_watcher.join().unwrap().context("Config watcher thread panicked")?;
-            keyboard_listener.join().unwrap().context("Keyboard listener thread panicked")?;
-        }
-        Err(e) => {
-            sender.send(Message::Quit).unwrap();
-            config_watcher.join().unwrap().context("Config watcher thread panicked")?;
-            keyboard_listener.join().unwrap().context("Keyboard listener thread panicked")?;
-            return Err(e);
-        }
+    let result = main_loop(app_state, &receiver);
+    runtime_state::save_metrics(&metrics);
+    // The config watcher waits on the file system with no way to wake it,
+    // so it isn't joined; it ends with the process.
+    drop(config_watcher);
+    if result.is_ok() {
+        keyboard_listener.join().unwrap().context("Keyboard listener thread panicked")?;
     }
-
-    Ok(())
+    result
 }
 
+/// How long `textra stop` waits for the daemon to quit by itself before
+/// terminating it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub fn handle_stop() -> Result<()> {
+    if keyboard::request_daemon_quit() {
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while is_service_running() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        if !is_service_running() {
+            showln!(gray_dim, "textra service ", red_bold, "stopped.");
+            return Ok(());
+        }
+    }
+
     let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
     if snapshot == INVALID_HANDLE_VALUE {
         return Err(anyhow::anyhow!("Failed to create process snapshot"));
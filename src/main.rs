@@ -14,7 +14,7 @@ use installer::*;
 use textra::*;
 use textra::config::*;
 use textra::keyboard::*;
-use anyhow::Result;
+use anyhow::{Context, Result};
  
 
 fn main() -> Result<()> {
@@ -22,6 +22,14 @@ fn main() -> Result<()> {
 //if applicaton is launched by double clicking the icon
 //we want window to stay open (usually it closes immediately)
 
+    // The first command someone runs tells them where their new config is.
+    // The daemon creates a missing one without a word.
+    if matches!(args.get(1).map(String::as_str), None | Some("run" | "start" | "config" | "edit" | "settings" | "install" | "setup")) {
+        if let Err(e) = bootstrap_config() {
+            eprintln!("Failed to create config: {}", e);
+        }
+    }
+
     if args.len() == 1 {
          display_help();
          //wait for 2 seconds before closing
@@ -31,16 +39,64 @@ fn main() -> Result<()> {
 
 
     match args[1].as_str() {
+        "run" | "start" if args.iter().any(|arg| arg == "--safe") => handle_safe_mode(),
         "run" | "start" => handle_run(),
+        "config" if args.get(2).map(String::as_str) == Some("migrate") => match args.get(3) {
+            Some(path) => handle_migrate_config(path),
+            None => {
+                showln!(gray_dim, "usage: ", cyan_bold, "textra config migrate PATH");
+                Ok(())
+            }
+        },
         "config" | "edit" | "settings" => {
             handle_edit_config().unwrap();
             Ok(())
         }
+        "validate" | "check" => handle_validate(config_source(&args)),
+        "list" | "ls" => handle_list(config_source(&args)),
+        "info" | "stats" => handle_info(config_source(&args)),
+        "match" => match args.get(2).filter(|arg| !arg.starts_with("--")) {
+            Some(text) => handle_match(text, flag_value(&args, "--config")),
+            None => {
+                showln!(gray_dim, "usage: ", cyan_bold, "textra match \"TEXT\" [--config FILE]");
+                Ok(())
+            }
+        },
+        "tokens" => textra::replacement::handle_tokens(),
+        "reset-rotation" => {
+            if let Some(path) = textra::rotation::state_path() {
+                textra::rotation::reset(&path)?;
+            }
+            showln!(gray_dim, "rotating snippets will start from their ", green_bold, "first option", gray_dim, " again.");
+            Ok(())
+        }
+        "status" => {
+            handle_display_status();
+            Ok(())
+        }
+        "selftest" | "self-test" => handle_selftest(),
+        "type" => handle_type(&args),
+        "share" => textra::share::handle_share(),
+        "import" => match args.get(2) {
+            Some(url) => textra::share::handle_import(url),
+            None => {
+                showln!(gray_dim, "usage: ", cyan_bold, "textra import URL");
+                Ok(())
+            }
+        },
+        "snooze" => handle_snooze(args.get(2).map(String::as_str)),
+        "where" => {
+            display_config_location()?;
+            Ok(())
+        }
         "daemon" | "service" => handle_daemon(),
         "stop" | "kill" => handle_stop(),
+        "install" | "setup" if args.iter().any(|arg| arg == "--dry-run") => handle_install_dry_run(),
         "install" | "setup" => handle_install(),
         "uninstall" | "remove" => handle_uninstall(),
         "update" => update_if_available(),
+        "rollback" => handle_rollback(),
+        "verify" => handle_verify(),
         _ => {
             match auto_install() {
                 Ok(_) => {
@@ -61,6 +117,55 @@ fn main() -> Result<()> {
 
 
 
+/// The config a command should read: `textra validate FILE`, `--config FILE`,
+/// or `-` in either place for stdin.
+fn config_source(args: &[String]) -> Option<&str> {
+    if let Some(i) = args.iter().position(|arg| arg == "--config") {
+        return args.get(i + 1).map(String::as_str);
+    }
+    args.get(2).map(String::as_str).filter(|arg| *arg == "-" || !arg.starts_with("--"))
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    args.get(i + 1).map(String::as_str)
+}
+
+/// `textra type TRIGGER --window TITLE [--class CLASS]`
+fn handle_type(args: &[String]) -> Result<()> {
+    let trigger = args.get(2).filter(|arg| !arg.starts_with("--"));
+    let query = WindowQuery {
+        title: flag_value(args, "--window").map(str::to_string),
+        class: flag_value(args, "--class").map(str::to_string),
+    };
+    match trigger {
+        Some(trigger) if query != WindowQuery::default() => type_into_window(trigger, &query),
+        _ => {
+            showln!(gray_dim, "usage: ", cyan_bold, "textra type TRIGGER --window TITLE", gray_dim, " or ", cyan_bold, "--class CLASS");
+            Ok(())
+        }
+    }
+}
+
+fn handle_snooze(duration: Option<&str>) -> Result<()> {
+    let path = textra::snooze::state_path().context("Failed to determine local data directory")?;
+    match duration {
+        Some("off") => {
+            textra::snooze::wake(&path)?;
+            showln!(gray_dim, "expansions are ", green_bold, "back on.");
+        }
+        Some(text) => match textra::snooze::parse_duration(text) {
+            Some(duration) => {
+                let until = textra::snooze::snooze(&path, duration)?;
+                showln!(gray_dim, "expansions are off until ", yellow_bold, until.format("%H:%M").to_string(), gray_dim, ".");
+            }
+            None => showln!(orange_bold, "invalid duration ", white_bold, text, gray_dim, ", use e.g. 30m, 2h or 45s."),
+        },
+        None => showln!(gray_dim, "usage: ", cyan_bold, "textra snooze 30m", gray_dim, " or ", cyan_bold, "textra snooze off"),
+    }
+    Ok(())
+}
+
 fn handle_display_status() {
     if is_service_running() {
         showln!(
@@ -81,6 +186,35 @@ fn handle_display_status() {
             "not running."
         );
     }
+    if let Some(left) = textra::snooze::state_path().and_then(|path| textra::snooze::remaining(&path)) {
+        showln!(
+            yellow_bold,
+            "│ ",
+            gray_dim,
+            "snoozed: ",
+            orange_bold,
+            format!("{} left.", textra::snooze::format_remaining(left))
+        );
+    }
+    if let Some(path) = textra::runtime_state::state_path() {
+        let state = textra::runtime_state::load(&path);
+        showln!(
+            yellow_bold,
+            "│ ",
+            gray_dim,
+            "keystrokes: ",
+            white_bold,
+            state.keystrokes_processed.to_string(),
+            gray_dim,
+            "  expansions: ",
+            white_bold,
+            state.expansions_performed.to_string(),
+            gray_dim,
+            "  average lookup: ",
+            white_bold,
+            format!("{:?}", state.average_lookup_time())
+        );
+    }
     if installer::check_autostart() {
         showln!(
             yellow_bold,
@@ -126,6 +260,14 @@ fn display_help() {
         gray_dim,
         "- Start the Textra service"
     );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra run --safe ",
+        gray_dim,
+        "- Run in this terminal with code rules off, to debug a config"
+    );
     showln!(
         yellow_bold,
         "│ ",
@@ -142,6 +284,14 @@ fn display_help() {
         gray_dim,
         "- Install Textra as a service"
     );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra install --dry-run ",
+        gray_dim,
+        "- Show what install would change without changing it"
+    );
     showln!(
         yellow_bold,
         "│ ",
@@ -150,6 +300,110 @@ fn display_help() {
         gray_dim,
         "- Uninstall the Textra service"
     );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra validate [file|-] ",
+        gray_dim,
+        "- Check a configuration file (or stdin) for errors"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra list [file|-] ",
+        gray_dim,
+        "- List the rules in a configuration file (or stdin)"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra info [file|-] ",
+        gray_dim,
+        "- Count the rules in a configuration file by kind and language"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra match TEXT ",
+        gray_dim,
+        "- Show which rule would expand at the end of TEXT, without typing it"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra tokens ",
+        gray_dim,
+        "- List the tokens replacements can contain, with what they type now"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra share ",
+        gray_dim,
+        "- Upload your rules and print a link to share them"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra import URL ",
+        gray_dim,
+        "- Add the rules someone shared to your config"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra reset-rotation ",
+        gray_dim,
+        "- Start rotating snippets from their first option again"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra snooze 30m|off ",
+        gray_dim,
+        "- Turn expansions off for a while, or back on"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra selftest ",
+        gray_dim,
+        "- Check that Textra can hear and type keys on this machine"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra rollback ",
+        gray_dim,
+        "- Go back to the version installed before the last update"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra verify ",
+        gray_dim,
+        "- Check the installed binaries against their recorded hashes"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra type TRIGGER --window TITLE ",
+        gray_dim,
+        "- Type a rule's replacement into another window"
+    );
     showln!(
         yellow_bold,
         "│ ",
@@ -166,6 +420,22 @@ fn display_help() {
         gray_dim,
         "- Edit the Textra configuration file"
     );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra where ",
+        gray_dim,
+        "- Show which configuration file is used"
+    );
+    showln!(
+        yellow_bold,
+        "│ ",
+        cyan_bold,
+        "textra config migrate PATH ",
+        gray_dim,
+        "- Move the configuration file, e.g. to ~/.config/textra"
+    );
     showln!(yellow_bold, "│ ");
 
     display_config();
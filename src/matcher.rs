@@ -0,0 +1,139 @@
+use crate::TextraRule;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Indexes every trigger of a config so the triggers typed text ends with can
+/// be found without trying each one. Triggers are stored back to front in a
+/// trie, and a lookup walks the typed text backwards from the cursor, so it
+/// visits at most as many characters as the longest trigger has, however many
+/// rules there are.
+///
+/// Triggers ending in `()` take parameters, so the text they match isn't known
+/// in advance. They're kept in a list and tried one by one.
+#[derive(Debug, Clone)]
+pub struct TriggerMatcher {
+    nodes: Vec<Node>,
+    parameterized: Vec<(usize, String)>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: HashMap<char, usize>,
+    /// Indices of the rules with a trigger that ends at this node.
+    rules: Vec<usize>,
+}
+
+impl Default for TriggerMatcher {
+    fn default() -> Self {
+        Self { nodes: vec![Node::default()], parameterized: Vec::new() }
+    }
+}
+
+impl TriggerMatcher {
+    pub fn new(rules: &[TextraRule]) -> Self {
+        let mut matcher = Self::default();
+        for (index, rule) in rules.iter().enumerate() {
+            for trigger in &rule.triggers {
+                if trigger.ends_with("()") {
+                    matcher.parameterized.push((index, trigger.clone()));
+                } else if !trigger.is_empty() {
+                    matcher.insert(trigger, index);
+                }
+            }
+        }
+        matcher
+    }
+
+    fn insert(&mut self, trigger: &str, rule: usize) {
+        let mut node = 0;
+        for c in trigger.chars().rev() {
+            node = match self.nodes[node].children.get(&c) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(c, child);
+                    child
+                }
+            };
+        }
+        let rules = &mut self.nodes[node].rules;
+        if !rules.contains(&rule) {
+            rules.push(rule);
+        }
+    }
+
+    /// The rule with the longest trigger that `buffer` ends with, and the end
+    /// of `buffer` it matched. Between rules with the same trigger the first
+    /// one wins.
+    pub fn longest_match<'t>(&self, buffer: &'t str) -> Option<(usize, &'t str)> {
+        self.matches(buffer).into_iter().next()
+    }
+
+    /// Every rule with a trigger that `buffer` ends with, longest trigger
+    /// first and in config order between triggers of the same length.
+    pub fn matches<'t>(&self, buffer: &'t str) -> Vec<(usize, &'t str)> {
+        let mut found = Vec::new();
+        let mut node = 0;
+        for (i, c) in buffer.char_indices().rev() {
+            node = match self.nodes[node].children.get(&c) {
+                Some(&child) => child,
+                None => break,
+            };
+            found.extend(self.nodes[node].rules.iter().map(|&rule| (rule, &buffer[i..])));
+        }
+        found.sort_by_key(|&(rule, typed)| (Reverse(typed.len()), rule));
+        found
+    }
+
+    /// The triggers ending in `()`, with the index of their rule.
+    pub fn parameterized(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.parameterized.iter().map(|(rule, trigger)| (*rule, trigger.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_textra_config;
+
+    fn matcher(input: &str) -> TriggerMatcher {
+        TriggerMatcher::new(&parse_textra_config(input).unwrap().rules)
+    }
+
+    #[test]
+    fn test_longest_trigger_wins() {
+        let matcher = matcher(":email => a@xo.rs\n:emailaddr | addr => Main Street 1\n");
+
+        assert_eq!(matcher.longest_match("send to :emailaddr"), Some((1, ":emailaddr")));
+        assert_eq!(matcher.longest_match("send to :email"), Some((0, ":email")));
+        assert_eq!(matcher.longest_match("my addr"), Some((1, "addr")));
+        assert_eq!(matcher.longest_match("send to :emai"), None);
+        assert_eq!(matcher.longest_match(""), None);
+    }
+
+    #[test]
+    fn test_matches_are_ordered() {
+        let matcher = matcher("mail => a\n:mail => b\nil => c\n:mail => d\n");
+
+        assert_eq!(matcher.matches("x:mail"), vec![(1, ":mail"), (3, ":mail"), (0, "mail"), (2, "il")]);
+    }
+
+    #[test]
+    fn test_parameterized_triggers_are_kept_apart() {
+        let matcher = matcher(":link() => <a href=\"{1}\">{2}</a>\n");
+
+        assert_eq!(matcher.longest_match(":link()"), None);
+        assert_eq!(matcher.parameterized().collect::<Vec<_>>(), vec![(0, ":link()")]);
+    }
+
+    #[test]
+    fn test_many_rules() {
+        let config: String = (0..5000).map(|i| format!(":r{i} => {i}\n")).collect();
+        let matcher = matcher(&config);
+
+        assert_eq!(matcher.longest_match(&format!("{}:r4999", "x".repeat(90))), Some((4999, ":r4999")));
+        assert_eq!(matcher.longest_match("so :r12"), Some((12, ":r12")));
+        assert_eq!(matcher.longest_match("nothing to see"), None);
+    }
+}
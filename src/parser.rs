@@ -1,7 +1,11 @@
 use pest::Parser;
 use pest_derive::Parser;
 use std::collections::HashMap;
-use pest::error::Error;
+use std::str::FromStr;
+use chrono::NaiveDate;
+use crate::MAX_TEXT_LENGTH;
+use crate::matcher::TriggerMatcher;
+use pest::error::{Error, ErrorVariant};
 use pest::iterators::Pair;
 
 // use crate::Suggestion;
@@ -15,12 +19,473 @@ pub struct TextraConfig {
     pub metadata: HashMap<String, String>,
     pub documentation: Vec<String>,
     pub rules: Vec<TextraRule>,
+    pub settings: TextraSettings,
+    /// Abbreviations of triggers, e.g. `:sgntr` for `:signature`, mapped to
+    /// the trigger. Only built when `fuzzy_abbreviations` is on.
+    pub abbreviations: HashMap<String, String>,
+    /// Index of the triggers in `rules`. Rebuild it with `index_triggers`
+    /// after changing the rules.
+    pub matcher: TriggerMatcher,
+}
+
+/// Options read from `///key:value` metadata lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextraSettings {
+    /// Key that aborts an expansion while it is still being typed.
+    pub cancel_key: String,
+    /// Replacements longer than this many characters are pasted through the
+    /// clipboard instead of typed. `0` always types.
+    pub paste_threshold: usize,
+    /// Wait for one of `commit_chars` after a trigger before expanding it.
+    pub expand_on_commit: bool,
+    /// Characters that end a trigger when `expand_on_commit` is on. Enter is
+    /// stored as `\n`.
+    pub commit_chars: String,
+    /// Characters that clear the typed text, so a trigger can't match across
+    /// them. Empty by default.
+    pub reset_chars: String,
+    /// Milliseconds without typing after which the typed text is forgotten.
+    /// `0` never forgets it.
+    pub buffer_timeout_ms: u64,
+    /// Forget the typed text on a mouse click or when another window gets
+    /// focus, since the cursor may no longer be after it. On by default.
+    pub reset_on_click: bool,
+    /// A character that has to be typed right before any trigger for it to
+    /// expand. It is deleted along with the trigger. Unset by default.
+    pub require_sigil: Option<char>,
+    /// Key that expands whatever trigger ends the buffer right now, even
+    /// before a commit character. Unset by default.
+    pub force_expand_key: Option<String>,
+    /// Replacement kinds (`simple`, `multiline`, `code`) that may expand.
+    /// Rules of any other kind are dropped when the config loads.
+    pub allowed_replacement_types: Vec<String>,
+    /// Line breaks used in multiline and code replacements.
+    pub line_endings: LineEnding,
+    /// What happens when a code replacement fails.
+    pub on_code_failure: CodeFailure,
+    /// Append every expansion to `expansions.jsonl` in the install directory.
+    pub expansion_log: bool,
+    /// Also write the typed text to the expansion log, not just its length.
+    pub expansion_log_text: bool,
+    /// Key pressed before deleting a trigger, e.g. `escape` to close an
+    /// editor's autocomplete popup so it can't put text back. Unset by default.
+    pub dismiss_key: Option<String>,
+    /// Executable names (e.g. `code.exe`) that get the dismiss key. Empty
+    /// means every app.
+    pub dismiss_apps: Vec<String>,
+    /// Longest trigger allowed. Longer ones could never match, since only
+    /// the last `MAX_TEXT_LENGTH` characters typed are kept.
+    pub max_trigger_length: usize,
+    /// Also expand abbreviations of triggers when nothing matches exactly.
+    pub fuzzy_abbreviations: bool,
+    /// Where `textra share` uploads the config. Unset uses paste.rs.
+    pub share_url: Option<String>,
+    /// Executable names (e.g. `keepass.exe`) that `// Secret` rules may
+    /// expand in. Empty means every app.
+    pub secret_apps: Vec<String>,
+    /// Milliseconds to wait after a trigger for typing to stop before
+    /// expanding it. `0` expands straight away.
+    pub settle_delay_ms: u64,
+    /// Whether replacements are typed as virtual keys or as scan codes.
+    pub input_mode: InputMode,
+    /// Vary the pause between typed keys by up to this many milliseconds,
+    /// for apps that reject perfectly even typing. `0` keeps it fixed.
+    pub humanize_jitter_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeFailure {
+    /// Type nothing and leave the trigger where it is.
+    KeepTrigger,
+    /// Replace the trigger with a short marker.
+    Marker,
+    /// Replace the trigger with the error message.
+    Error,
+}
+
+impl FromStr for CodeFailure {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" | "keep_trigger" => Ok(CodeFailure::KeepTrigger),
+            "marker" => Ok(CodeFailure::Marker),
+            "error" => Ok(CodeFailure::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+impl FromStr for LineEnding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::Crlf),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How simulated key presses identify their key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputMode {
+    /// Virtual key codes, which most apps translate through the keyboard layout.
+    VirtualKey,
+    /// Hardware scan codes, for games and other apps that read them directly.
+    ScanCode,
+}
+
+impl FromStr for InputMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "vk" | "virtual_key" => Ok(InputMode::VirtualKey),
+            "scancode" | "scan_code" => Ok(InputMode::ScanCode),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Rewrites every `\r\n`, `\r` or `\n` in `text` as `line_ending`.
+pub fn normalize_line_endings(text: &str, line_ending: LineEnding) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n").replace('\n', line_ending.as_str())
+}
+
+impl Default for TextraSettings {
+    fn default() -> Self {
+        Self {
+            cancel_key: "escape".to_string(),
+            paste_threshold: 50,
+            expand_on_commit: false,
+            commit_chars: " \t\n.,;!?".to_string(),
+            reset_chars: String::new(),
+            buffer_timeout_ms: 1000,
+            reset_on_click: true,
+            require_sigil: None,
+            force_expand_key: None,
+            allowed_replacement_types: REPLACEMENT_KINDS.iter().map(|kind| kind.to_string()).collect(),
+            line_endings: LineEnding::Lf,
+            on_code_failure: CodeFailure::KeepTrigger,
+            expansion_log: false,
+            expansion_log_text: false,
+            dismiss_key: None,
+            dismiss_apps: Vec::new(),
+            max_trigger_length: MAX_TEXT_LENGTH,
+            fuzzy_abbreviations: false,
+            share_url: None,
+            secret_apps: Vec::new(),
+            settle_delay_ms: 0,
+            input_mode: InputMode::VirtualKey,
+            humanize_jitter_ms: 0,
+        }
+    }
+}
+
+impl TextraSettings {
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        let mut settings = Self::default();
+        if let Some(cancel_key) = metadata.get("cancel_key") {
+            settings.cancel_key = cancel_key.trim().to_lowercase();
+        }
+        if let Some(paste_threshold) = parse_setting(metadata, "paste_threshold") {
+            settings.paste_threshold = paste_threshold;
+        }
+        if let Some(expand_on_commit) = parse_setting(metadata, "expand_on_commit") {
+            settings.expand_on_commit = expand_on_commit;
+        }
+        if let Some(commit_chars) = metadata.get("commit_chars") {
+            settings.commit_chars = unescape_chars(commit_chars.trim());
+        }
+        if let Some(buffer_timeout_ms) = parse_setting(metadata, "buffer_timeout_ms") {
+            settings.buffer_timeout_ms = buffer_timeout_ms;
+        }
+        if let Some(reset_on_click) = parse_setting(metadata, "reset_on_click") {
+            settings.reset_on_click = reset_on_click;
+        }
+        if let Some(require_sigil) = metadata.get("require_sigil") {
+            let mut chars = require_sigil.trim().chars();
+            settings.require_sigil = chars.next().filter(|_| chars.next().is_none());
+        }
+        if let Some(reset_chars) = metadata.get("reset_chars") {
+            settings.reset_chars = unescape_chars(reset_chars.trim());
+        }
+        if let Some(force_expand_key) = metadata.get("force_expand_key") {
+            let force_expand_key = force_expand_key.trim().to_lowercase();
+            settings.force_expand_key = Some(force_expand_key).filter(|key| !key.is_empty());
+        }
+        if let Some(dismiss_key) = metadata.get("dismiss_key") {
+            let dismiss_key = dismiss_key.trim().to_lowercase();
+            settings.dismiss_key = Some(dismiss_key).filter(|key| !key.is_empty());
+        }
+        if let Some(share_url) = metadata.get("share_url") {
+            settings.share_url = Some(share_url.trim().to_string()).filter(|url| !url.is_empty());
+        }
+        if let Some(dismiss_apps) = metadata.get("dismiss_apps") {
+            settings.dismiss_apps = dismiss_apps
+                .split(',')
+                .map(|app| app.trim().to_lowercase())
+                .filter(|app| !app.is_empty())
+                .collect();
+        }
+        if let Some(secret_apps) = metadata.get("secret_apps") {
+            settings.secret_apps = secret_apps
+                .split(',')
+                .map(|app| app.trim().to_lowercase())
+                .filter(|app| !app.is_empty())
+                .collect();
+        }
+        if let Some(max_trigger_length) = parse_setting::<usize>(metadata, "max_trigger_length") {
+            settings.max_trigger_length = max_trigger_length.min(MAX_TEXT_LENGTH);
+        }
+        if let Some(settle_delay_ms) = parse_setting(metadata, "settle_delay_ms") {
+            settings.settle_delay_ms = settle_delay_ms;
+        }
+        if let Some(fuzzy_abbreviations) = parse_setting(metadata, "fuzzy_abbreviations") {
+            settings.fuzzy_abbreviations = fuzzy_abbreviations;
+        }
+        if let Some(expansion_log) = parse_setting(metadata, "expansion_log") {
+            settings.expansion_log = expansion_log;
+        }
+        if let Some(expansion_log_text) = parse_setting(metadata, "expansion_log_text") {
+            settings.expansion_log_text = expansion_log_text;
+        }
+        if let Some(on_code_failure) = parse_setting(metadata, "on_code_failure") {
+            settings.on_code_failure = on_code_failure;
+        }
+        if let Some(humanize_jitter_ms) = parse_setting(metadata, "humanize_jitter_ms") {
+            settings.humanize_jitter_ms = humanize_jitter_ms;
+        }
+        if let Some(input_mode) = parse_setting(metadata, "input_mode") {
+            settings.input_mode = input_mode;
+        }
+        if let Some(line_endings) = parse_setting(metadata, "line_endings") {
+            settings.line_endings = line_endings;
+        }
+        if let Some(allowed) = metadata.get("allowed_replacement_types") {
+            let allowed: Vec<String> = allowed
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .map(|kind| kind.trim().to_lowercase())
+                .filter(|kind| REPLACEMENT_KINDS.contains(&kind.as_str()))
+                .collect();
+            if !allowed.is_empty() {
+                settings.allowed_replacement_types = allowed;
+            }
+        }
+        settings
+    }
+
+    pub fn allows(&self, replacement: &Replacement) -> bool {
+        self.allowed_replacement_types.iter().any(|kind| kind == replacement.kind())
+    }
+
+    pub fn is_commit_char(&self, c: char) -> bool {
+        let c = if c == '\r' { '\n' } else { c };
+        self.commit_chars.contains(c)
+    }
+
+    pub fn is_reset_char(&self, c: char) -> bool {
+        let c = if c == '\r' { '\n' } else { c };
+        self.reset_chars.contains(c)
+    }
+}
+
+/// Decodes `\s` (space), `\t` (tab), `\n` (enter) and `\\` in a setting value,
+/// since surrounding whitespace is trimmed.
+fn unescape_chars(value: &str) -> String {
+    let mut output = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => output.push(' '),
+            Some('t') => output.push('\t'),
+            Some('n') => output.push('\n'),
+            Some(other) => output.push(other),
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+fn parse_setting<T: FromStr>(metadata: &HashMap<String, String>, key: &str) -> Option<T> {
+    metadata.get(key)?.trim().parse().ok()
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TextraRule {
     pub triggers: Vec<String>,
     pub replacement: Replacement,
+    pub options: RuleOptions,
+    /// The `//` comment lines right above the rule, one per line.
+    pub description: Option<String>,
+}
+
+/// Per-rule options, written as `// Key: value` lines right above the rule.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RuleOptions {
+    /// Last day the rule expands on.
+    pub expires: Option<NaiveDate>,
+    /// First day the rule expands on.
+    pub active_from: Option<NaiveDate>,
+    /// Type the replacement exactly, without expanding tokens or matching
+    /// the trigger's case.
+    pub literal: bool,
+    /// Only expand once the next character typed doesn't continue the word.
+    pub word_end: bool,
+    /// Only expand when the trigger is the first thing on its line.
+    pub line_start: bool,
+    /// Leave a trigger's leading punctuation (the `:` in `:email`) in place.
+    pub keep_prefix: bool,
+    /// Type one option at a time, in turn. Options are separated by `---`
+    /// lines.
+    pub rotate: bool,
+    /// List the rule ahead of unpinned ones.
+    pub pinned: bool,
+    /// Position in listings; lower comes first, unordered rules last.
+    pub order: Option<i32>,
+    /// Leave the rule out of `textra share`.
+    pub private: bool,
+    /// Show the replacement as `***` in listings, shared configs and logs.
+    /// It still expands normally.
+    pub secret: bool,
+    /// Ask before typing the replacement.
+    pub confirm: bool,
+    /// Match the trigger as a whole word anywhere on the current line, not
+    /// just where the cursor is, and fix it in place.
+    pub anywhere: bool,
+    /// What the rule is for, e.g. `Work`, to sort rules by.
+    pub category: Option<String>,
+}
+
+impl RuleOptions {
+    /// Applies one annotation. Unknown keys are ignored so that ordinary
+    /// capitalised comments like `// TODO` don't break the config.
+    pub fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match normalize_annotation_key(key).as_str() {
+            "expires" => self.expires = Some(parse_date(value)?),
+            "activefrom" => self.active_from = Some(parse_date(value)?),
+            "literal" => self.literal = parse_flag(value)?,
+            "wordend" => self.word_end = parse_flag(value)?,
+            "linestart" => self.line_start = parse_flag(value)?,
+            "keepprefix" => self.keep_prefix = parse_flag(value)?,
+            "rotate" => self.rotate = parse_flag(value)?,
+            "pinned" => self.pinned = parse_flag(value)?,
+            "order" => self.order = Some(parse_order(value)?),
+            "private" => self.private = parse_flag(value)?,
+            "secret" => self.secret = parse_flag(value)?,
+            "confirm" => self.confirm = parse_flag(value)?,
+            "anywhere" => self.anywhere = parse_flag(value)?,
+            "category" => self.category = Some(parse_category(value)?),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn is_active_on(&self, date: NaiveDate) -> bool {
+        self.active_from.map_or(true, |from| date >= from)
+            && self.expires.map_or(true, |expires| date <= expires)
+    }
+
+    /// The annotation lines that reproduce these options, without `// `.
+    pub fn annotations(&self) -> Vec<String> {
+        let mut annotations = Vec::new();
+        if let Some(active_from) = self.active_from {
+            annotations.push(format!("ActiveFrom: {active_from}"));
+        }
+        if let Some(expires) = self.expires {
+            annotations.push(format!("Expires: {expires}"));
+        }
+        if self.literal {
+            annotations.push("Literal".to_string());
+        }
+        if self.word_end {
+            annotations.push("WordEnd".to_string());
+        }
+        if self.line_start {
+            annotations.push("LineStart".to_string());
+        }
+        if self.keep_prefix {
+            annotations.push("KeepPrefix".to_string());
+        }
+        if self.rotate {
+            annotations.push("Rotate".to_string());
+        }
+        if self.pinned {
+            annotations.push("Pinned".to_string());
+        }
+        if let Some(order) = self.order {
+            annotations.push(format!("Order: {order}"));
+        }
+        if self.private {
+            annotations.push("Private".to_string());
+        }
+        if self.secret {
+            annotations.push("Secret".to_string());
+        }
+        if self.confirm {
+            annotations.push("Confirm".to_string());
+        }
+        if self.anywhere {
+            annotations.push("Anywhere".to_string());
+        }
+        if let Some(category) = &self.category {
+            annotations.push(format!("Category: {category}"));
+        }
+        annotations
+    }
+}
+
+fn normalize_annotation_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| *c != '-' && *c != '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// A bare `// Key` turns a flag on; `true` and `false` can also be spelled out.
+fn parse_flag(value: &str) -> Result<bool, String> {
+    match value.trim().to_lowercase().as_str() {
+        "" | "true" => Ok(true),
+        "false" => Ok(false),
+        other => Err(format!("expected true or false, found '{other}'")),
+    }
+}
+
+fn parse_order(value: &str) -> Result<i32, String> {
+    value.trim().parse().map_err(|_| format!("expected a number, found '{}'", value.trim()))
+}
+
+fn parse_category(value: &str) -> Result<String, String> {
+    match value.trim() {
+        "" => Err("expected a category name".to_string()),
+        category => Ok(category.to_string()),
+    }
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("expected a date like 2024-12-31, found '{}'", value.trim()))
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -30,9 +495,43 @@ pub enum Replacement {
     Code { language: String, content: String },
 }
 
+const REPLACEMENT_KINDS: [&str; 3] = ["simple", "multiline", "code"];
+
+impl Replacement {
+    /// The name used for this kind of replacement in settings.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Replacement::Simple(_) => "simple",
+            Replacement::Multiline(_) => "multiline",
+            Replacement::Code { .. } => "code",
+        }
+    }
+}
+
+/// Shown instead of the replacement of a `// Secret` rule.
+pub const SECRET_MASK: &str = "***";
+
+impl TextraRule {
+    /// The replacement as it may be shown to people: code for code rules,
+    /// and `***` for secrets.
+    pub fn shown_replacement(&self) -> &str {
+        if self.options.secret {
+            return SECRET_MASK;
+        }
+        match &self.replacement {
+            Replacement::Simple(text) | Replacement::Multiline(text) => text,
+            Replacement::Code { content, .. } => content,
+        }
+    }
+}
+
 pub type ParseError = pest::error::Error<Rule>;
 
 impl TextraConfig {
+    pub fn index_triggers(&mut self) {
+        self.matcher = TriggerMatcher::new(&self.rules);
+    }
+
     // pub fn get_suggestions(&self, current_text: &str) -> Vec<Suggestion> {
     //     let mut suggestions = Vec::new();
     //     for rule in &self.rules {
@@ -103,9 +602,15 @@ pub fn parse_textra_config(input: &str) -> Result<TextraConfig, Error<Rule>> {
         metadata: HashMap::new(),
         documentation: Vec::new(),
         rules: Vec::new(),
+        settings: TextraSettings::default(),
+        abbreviations: HashMap::new(),
+        matcher: TriggerMatcher::default(),
     };
 
     let pairs = TextraParser::parse(Rule::file, input)?;
+    // Rules are parsed after the settings, which can appear anywhere. Group
+    // headers stay in line with them, since they apply to the rules below.
+    let mut rule_pairs = Vec::new();
 
     for pair in pairs {
         match pair.as_rule() {
@@ -114,7 +619,9 @@ pub fn parse_textra_config(input: &str) -> Result<TextraConfig, Error<Rule>> {
                     match inner_pair.as_rule() {
                         Rule::metadata => parse_metadata(&mut config, inner_pair),
                         Rule::documentation => parse_documentation(&mut config, inner_pair),
-                        Rule::rule => parse_rule(&mut config, inner_pair),
+                        Rule::group | Rule::rule => rule_pairs.push(inner_pair),
+                        // An annotation with no rule after it.
+                        Rule::annotation => {}
                         Rule::EOI => {}
                         _ => unreachable!(),
                     }
@@ -124,9 +631,93 @@ pub fn parse_textra_config(input: &str) -> Result<TextraConfig, Error<Rule>> {
         }
     }
 
+    config.settings = TextraSettings::from_metadata(&config.metadata);
+    let mut group_options = RuleOptions::default();
+    for pair in rule_pairs {
+        match pair.as_rule() {
+            Rule::group => group_options = parse_group(pair)?,
+            _ => parse_rule(&mut config, pair, &group_options, input)?,
+        }
+    }
+    let line_endings = config.settings.line_endings;
+    for rule in &mut config.rules {
+        if let Replacement::Multiline(text) = &mut rule.replacement {
+            *text = normalize_line_endings(text, line_endings);
+        }
+    }
+    if config.settings.fuzzy_abbreviations {
+        config.abbreviations = build_abbreviations(&config.rules);
+    }
+    config.index_triggers();
     Ok(config)
 }
 
+/// Shortest abbreviation that is indexed. Shorter ones fire too easily.
+const MIN_ABBREVIATION_LENGTH: usize = 3;
+
+/// Maps the abbreviations of every trigger to it. An abbreviation that two
+/// rules share, or that is already a trigger, is left out.
+fn build_abbreviations(rules: &[TextraRule]) -> HashMap<String, String> {
+    let triggers: Vec<&String> = rules.iter().flat_map(|rule| &rule.triggers).collect();
+    // `None` once a second rule claims the abbreviation.
+    let mut candidates: HashMap<String, Option<(usize, &String)>> = HashMap::new();
+    for (index, rule) in rules.iter().enumerate() {
+        for trigger in &rule.triggers {
+            for abbreviation in abbreviations_of(trigger) {
+                let entry = candidates.entry(abbreviation).or_insert(Some((index, trigger)));
+                if entry.map_or(true, |(owner, _)| owner != index) {
+                    *entry = None;
+                }
+            }
+        }
+    }
+    candidates
+        .into_iter()
+        .filter(|(abbreviation, _)| !triggers.iter().any(|trigger| *trigger == abbreviation))
+        .filter_map(|(abbreviation, owner)| Some((abbreviation, owner?.1.clone())))
+        .collect()
+}
+
+/// The initials of a camelCase trigger (`tyvm` for `thankYouVeryMuch`) and
+/// its consonants (`sgntr` for `signature`), keeping any leading symbol.
+fn abbreviations_of(trigger: &str) -> Vec<String> {
+    if trigger.ends_with("()") {
+        return Vec::new();
+    }
+    let body_start = trigger.find(|c: char| c.is_alphanumeric()).unwrap_or(trigger.len());
+    let (prefix, body) = trigger.split_at(body_start);
+    let mut abbreviations = Vec::new();
+
+    let mut initials = String::new();
+    let mut previous: Option<char> = None;
+    for c in body.chars() {
+        let starts_word = match previous {
+            None => true,
+            Some(p) => (c.is_uppercase() && p.is_lowercase()) || (c.is_ascii_digit() != p.is_ascii_digit()),
+        };
+        if starts_word {
+            initials.extend(c.to_lowercase());
+        }
+        previous = Some(c);
+    }
+    if initials.chars().count() >= MIN_ABBREVIATION_LENGTH {
+        abbreviations.push(format!("{prefix}{initials}"));
+    }
+
+    let lower = body.to_lowercase();
+    let mut chars = lower.chars();
+    let skeleton: String = chars
+        .next()
+        .into_iter()
+        .chain(chars.filter(|c| !"aeiou".contains(*c)))
+        .collect();
+    let skeleton_len = skeleton.chars().count();
+    if skeleton_len >= MIN_ABBREVIATION_LENGTH && skeleton_len < lower.chars().count() && skeleton != initials {
+        abbreviations.push(format!("{prefix}{skeleton}"));
+    }
+    abbreviations
+}
+
 fn parse_metadata(config: &mut TextraConfig, pair: Pair<Rule>) {
     let mut inner = pair.into_inner();
     let key = inner.next().unwrap().as_str().to_string();
@@ -139,20 +730,87 @@ fn parse_documentation(config: &mut TextraConfig, pair: Pair<Rule>) {
     config.documentation.push(doc);
 }
 
-fn parse_rule(config: &mut TextraConfig, pair: Pair<Rule>) {
+/// Reads the options of a `[name: Option, Option: value]` header. They are
+/// the starting point for every rule up to the next header.
+fn parse_group(pair: Pair<Rule>) -> Result<RuleOptions, ParseError> {
+    let mut options = RuleOptions::default();
+    for option in pair.into_inner().skip(1) {
+        let span = option.as_span();
+        let mut inner = option.into_inner();
+        let key = inner.next().unwrap().as_str();
+        let value = inner.next().map_or("", |value| value.as_str());
+        options.apply(key, value).map_err(|message| {
+            ParseError::new_from_span(ErrorVariant::CustomError { message: format!("{key}: {message}") }, span)
+        })?;
+    }
+    Ok(options)
+}
+
+fn parse_rule(config: &mut TextraConfig, pair: Pair<Rule>, group_options: &RuleOptions, input: &str) -> Result<(), ParseError> {
+    let mut options = group_options.clone();
     let mut inner = pair.into_inner();
-    let triggers = parse_triggers(inner.next().unwrap());
+    let mut next = inner.next().unwrap();
+    while next.as_rule() == Rule::annotation {
+        parse_annotation(&mut options, next)?;
+        next = inner.next().unwrap();
+    }
+    let description = description_above(&input[..next.as_span().start()]);
+    let triggers = parse_triggers(next, config.settings.max_trigger_length)?;
     let replacement = parse_replacement(inner.next().unwrap());
 
     config.rules.push(TextraRule {
         triggers,
         replacement,
+        options,
+        description,
     });
+    Ok(())
+}
+
+/// Collects the `//` comments right above a rule's triggers in `before`,
+/// passing over its annotations. A blank line or anything other than a
+/// comment ends the description.
+fn description_above(before: &str) -> Option<String> {
+    let mut lines = Vec::new();
+    for line in before.trim_end_matches(|c| c == ' ' || c == '\t').lines().rev() {
+        let line = line.trim();
+        if line.starts_with("///") || !line.starts_with("//") {
+            break;
+        }
+        if TextraParser::parse(Rule::annotation, &format!("{line}\n")).is_err() {
+            lines.push(line.trim_start_matches('/').trim());
+        }
+    }
+    lines.reverse();
+    (!lines.is_empty()).then(|| lines.join("\n"))
 }
 
-fn parse_triggers(pair: Pair<Rule>) -> Vec<String> {
+fn parse_annotation(options: &mut RuleOptions, pair: Pair<Rule>) -> Result<(), ParseError> {
+    let span = pair.as_span();
+    let mut inner = pair.into_inner();
+    let key = inner.next().unwrap().as_str();
+    let value = inner.next().map_or("", |value| value.as_str());
+    options.apply(key, value).map_err(|message| {
+        ParseError::new_from_span(ErrorVariant::CustomError { message: format!("{key}: {message}") }, span)
+    })
+}
+
+fn parse_triggers(pair: Pair<Rule>, max_length: usize) -> Result<Vec<String>, ParseError> {
     pair.into_inner()
-        .map(|trigger| trigger.as_str().trim().to_string())
+        .map(|pair| {
+            let text = pair.as_str().trim();
+            // A trigger in backticks is taken as written, spaces included.
+            let trigger = match text.strip_prefix('`').and_then(|text| text.strip_suffix('`')) {
+                Some(quoted) => quoted.to_string(),
+                None => text.replace("\\|", "|"),
+            };
+            let length = trigger.chars().count();
+            if length > max_length {
+                let message = format!("trigger is {length} characters long, the longest that can match is {max_length}");
+                return Err(ParseError::new_from_span(ErrorVariant::CustomError { message }, pair.as_span()));
+            }
+            Ok(trigger)
+        })
         .collect()
 }
 
@@ -179,6 +837,14 @@ fn parse_replacement(pair: Pair<Rule>) -> Replacement {
     }
 }
 
+/// Whether `trigger` can be written without backticks: letters and digits,
+/// optionally after a `:` and before `()`.
+fn is_bare_trigger(trigger: &str) -> bool {
+    let body = trigger.strip_prefix(':').unwrap_or(trigger);
+    let body = body.strip_suffix("()").unwrap_or(body);
+    !body.is_empty() && body.chars().all(|c| c.is_ascii_alphanumeric() || c == '|')
+}
+
 pub fn serialize_textra_config(config: &TextraConfig) -> String {
     let mut output = String::new();
 
@@ -191,7 +857,21 @@ pub fn serialize_textra_config(config: &TextraConfig) -> String {
     }
 
     for rule in &config.rules {
-        let triggers = rule.triggers.join(" | ");
+        for line in rule.description.iter().flat_map(|description| description.lines()) {
+            output.push_str(&format!("// {line}\n"));
+        }
+        for annotation in rule.options.annotations() {
+            output.push_str(&format!("// {annotation}\n"));
+        }
+        let triggers: Vec<String> = rule
+            .triggers
+            .iter()
+            .map(|trigger| match is_bare_trigger(trigger) {
+                true => trigger.replace('|', "\\|"),
+                false => format!("`{trigger}`"),
+            })
+            .collect();
+        let triggers = triggers.join(" | ");
         let replacement = match &rule.replacement {
             Replacement::Simple(s) => s.to_string(),
             Replacement::Multiline(s) => format!("`{s}`"),
@@ -215,6 +895,16 @@ mod tests {
         assert_eq!(config.metadata.get("name"), Some(&"Textra Config Example".to_string()));
     }
 
+    #[test]
+    fn test_metadata_with_space_after_slashes() {
+        let input = "/// paste_threshold: 10\n///  expand_on_commit:true\n/// Some docs.\nbtw => by the way\n";
+        let config = parse_textra_config(input).expect("Failed to parse metadata");
+
+        assert_eq!(config.settings.paste_threshold, 10);
+        assert!(config.settings.expand_on_commit);
+        assert_eq!(config.documentation, vec!["Some docs.".to_string()]);
+    }
+
     #[test]
     fn test_parse_documentation() {
         let input = "/// This is a Textra configuration file.\n";
@@ -223,6 +913,56 @@ mod tests {
         assert_eq!(config.documentation, vec!["This is a Textra configuration file.".to_string()]);
     }
 
+    #[test]
+    fn test_parse_settings() {
+        let input = "///cancel_key: F12\n///paste_threshold:200\n///settle_delay_ms:40\n///input_mode:ScanCode\n/// Some docs.\nbtw => by the way\n";
+        let config = parse_textra_config(input).expect("Failed to parse settings");
+
+        assert_eq!(config.settings.cancel_key, "f12");
+        assert_eq!(config.settings.paste_threshold, 200);
+        assert_eq!(config.settings.settle_delay_ms, 40);
+        assert_eq!(config.settings.input_mode, InputMode::ScanCode);
+        assert_eq!(config.documentation, vec!["Some docs.".to_string()]);
+        assert_eq!(config.rules.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_commit_chars() {
+        let input = "///expand_on_commit:true\n///commit_chars:\\s\\t\\n.-\nbtw => by the way\n";
+        let config = parse_textra_config(input).expect("Failed to parse settings");
+
+        assert!(config.settings.expand_on_commit);
+        assert_eq!(config.settings.commit_chars, " \t\n.-");
+        assert!(config.settings.is_commit_char('\r'));
+        assert!(!config.settings.is_commit_char(','));
+    }
+
+    #[test]
+    fn test_invalid_setting_falls_back_to_default() {
+        let input = "///paste_threshold:lots\nbtw => by the way\n";
+        let config = parse_textra_config(input).expect("Failed to parse settings");
+
+        assert_eq!(config.settings.paste_threshold, 50);
+    }
+
+    #[test]
+    fn test_buffer_timeout_setting() {
+        let timeout = |input: &str| parse_textra_config(input).expect("Failed to parse settings").settings.buffer_timeout_ms;
+
+        assert_eq!(timeout("///buffer_timeout_ms:2500\nbtw => by the way\n"), 2500);
+        assert_eq!(timeout("///buffer_timeout_ms: 0\nbtw => by the way\n"), 0);
+        assert_eq!(timeout("btw => by the way\n"), 1000);
+        assert_eq!(timeout("///buffer_timeout_ms:2.5s\nbtw => by the way\n"), 1000);
+        assert_eq!(timeout("///buffer_timeout_ms:-1\nbtw => by the way\n"), 1000);
+    }
+
+    #[test]
+    fn test_default_settings() {
+        let config = parse_textra_config("btw => by the way\n").expect("Failed to parse rule");
+
+        assert_eq!(config.settings, TextraSettings::default());
+    }
+
     #[test]
     fn test_parse_simple_rule() {
         let input = "btw => by the way\n";
@@ -243,6 +983,186 @@ mod tests {
         assert_eq!(config.rules[0].replacement, Replacement::Simple("a@xo.rs".to_string()));
     }
 
+    #[test]
+    fn test_parse_rule_expiration() {
+        let input = "// ActiveFrom: 2024-12-01\n// Expires: 2024-12-31\n:tag => #launchweek\n";
+        let config = parse_textra_config(input).expect("Failed to parse annotated rule");
+        let options = &config.rules[0].options;
+
+        assert_eq!(options.active_from, NaiveDate::from_ymd_opt(2024, 12, 1));
+        assert_eq!(options.expires, NaiveDate::from_ymd_opt(2024, 12, 31));
+        assert!(!options.is_active_on(NaiveDate::from_ymd_opt(2024, 11, 30).unwrap()));
+        assert!(options.is_active_on(NaiveDate::from_ymd_opt(2024, 12, 31).unwrap()));
+        assert!(!options.is_active_on(NaiveDate::from_ymd_opt(2025, 1, 1).unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_expiration_is_an_error() {
+        let input = "// Expires: soon\nbtw => by the way\n";
+        assert!(parse_textra_config(input).is_err());
+    }
+
+    #[test]
+    fn test_plain_comments_are_not_annotations() {
+        let input = "// TODO\n// this is a comment\nbtw => by the way\n// Trailing\n";
+        let config = parse_textra_config(input).expect("Failed to parse comments");
+
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.rules[0].options, RuleOptions::default());
+    }
+
+    #[test]
+    fn test_serialize_round_trips_annotations() {
+        let input = "// Expires: 2024-12-31\n// Literal\nbtw => by the way\n";
+        let config = parse_textra_config(input).expect("Failed to parse annotated rule");
+        assert!(config.rules[0].options.literal);
+        let reparsed = parse_textra_config(&serialize_textra_config(&config)).expect("Failed to reparse");
+
+        assert_eq!(reparsed.rules, config.rules);
+    }
+
+    #[test]
+    fn test_descriptions_and_categories() {
+        let input = "// Not about anything
+
+// My work address,
+// for signatures
+// Category: Work
+// TODO
+:email => a@xo.rs
+/// Some docs.
+// Category: Chat
+brb => be right back
+";
+        let config = parse_textra_config(input).expect("Failed to parse described rules");
+        assert_eq!(config.rules[0].description.as_deref(), Some("My work address,\nfor signatures"));
+        assert_eq!(config.rules[0].options.category.as_deref(), Some("Work"));
+        assert_eq!(config.rules[1].description, None);
+        assert_eq!(config.rules[1].options.category.as_deref(), Some("Chat"));
+
+        let serialized = serialize_textra_config(&config);
+        assert!(serialized.contains("// My work address,\n// for signatures\n// Category: Work\n:email => a@xo.rs\n"));
+        assert_eq!(parse_textra_config(&serialized).unwrap().rules, config.rules);
+        assert!(parse_textra_config("// Category:\nbtw => by the way\n").is_err());
+    }
+
+    #[test]
+    fn test_group_options_apply_to_its_rules() {
+        let input = "before => a
+[launch: WordEnd, Expires: 2024-12-31]
+:tag => #launchweek
+// WordEnd: false
+// Expires: 2025-01-31
+:date => 2024-12-01
+[other]
+after => b
+";
+        let config = parse_textra_config(input).expect("Failed to parse groups");
+
+        assert_eq!(config.rules[0].options, RuleOptions::default());
+        assert!(config.rules[1].options.word_end);
+        assert_eq!(config.rules[1].options.expires, NaiveDate::from_ymd_opt(2024, 12, 31));
+        assert!(!config.rules[2].options.word_end);
+        assert_eq!(config.rules[2].options.expires, NaiveDate::from_ymd_opt(2025, 1, 31));
+        assert_eq!(config.rules[3].options, RuleOptions::default());
+    }
+
+    #[test]
+    fn test_invalid_group_option_is_an_error() {
+        assert!(parse_textra_config("[launch: Expires: soon]
+:tag => #launchweek
+").is_err());
+    }
+
+    #[test]
+    fn test_quoted_triggers_and_trailing_spaces_are_kept() {
+        let input = "`sig ` | `:e-mail` | a\\|b => Best regards, \n";
+        let config = parse_textra_config(input).expect("Failed to parse quoted triggers");
+
+        assert_eq!(config.rules[0].triggers, vec!["sig ".to_string(), ":e-mail".to_string(), "a|b".to_string()]);
+        assert_eq!(config.rules[0].replacement, Replacement::Simple("Best regards, ".to_string()));
+        assert_eq!(serialize_textra_config(&config), input);
+    }
+
+    #[test]
+    fn test_serialize_round_trips_pinned_and_order() {
+        let input = "// Pinned
+// Order: 2
+:sig => regards
+// Order: -1
+btw => by the way
+";
+        let config = parse_textra_config(input).expect("Failed to parse ordered rules");
+        assert!(config.rules[0].options.pinned);
+        assert_eq!(config.rules[0].options.order, Some(2));
+        assert_eq!(config.rules[1].options.order, Some(-1));
+
+        assert_eq!(serialize_textra_config(&config), input);
+        assert!(parse_textra_config("// Order: first
+btw => by the way
+").is_err());
+    }
+
+    #[test]
+    fn test_fuzzy_abbreviations() {
+        let input = "///fuzzy_abbreviations:true\nthankYouVeryMuch => thank you very much\n:signature => Best regards\nbrb => be right back\n";
+        let config = parse_textra_config(input).expect("Failed to parse config");
+        let lookup = |abbreviation: &str| config.abbreviations.get(abbreviation).map(String::as_str);
+
+        assert_eq!(lookup("tyvm"), Some("thankYouVeryMuch"));
+        assert_eq!(lookup("thnkyvrymch"), Some("thankYouVeryMuch"));
+        assert_eq!(lookup(":sgntr"), Some(":signature"));
+        // Too short to index, and not shorter than the trigger.
+        assert_eq!(lookup("brb"), None);
+
+        let config = parse_textra_config("thankYouVeryMuch => thanks\n").expect("Failed to parse config");
+        assert!(config.abbreviations.is_empty());
+    }
+
+    #[test]
+    fn test_ambiguous_abbreviations_are_dropped() {
+        let input = "///fuzzy_abbreviations:true\nsignature => a\nsgntr => b\nsignator => c\nhappyBirthdayDear => d\nhappyBirthdayDad => e\n";
+        let config = parse_textra_config(input).expect("Failed to parse config");
+
+        assert_eq!(config.abbreviations.get("sgntr"), None);
+        assert_eq!(config.abbreviations.get("hbd"), None);
+        assert_eq!(config.abbreviations.get("hppybrthdydr").map(String::as_str), Some("happyBirthdayDear"));
+    }
+
+    #[test]
+    fn test_multiline_line_endings_are_normalized() {
+        let input = ":sig => `a\r\nb\nc`\n";
+        let config = parse_textra_config(input).expect("Failed to parse multiline replacement");
+        assert_eq!(config.rules[0].replacement, Replacement::Multiline("a\nb\nc".to_string()));
+
+        let input = format!("///line_endings:CRLF\n{input}");
+        let config = parse_textra_config(&input).expect("Failed to parse multiline replacement");
+        assert_eq!(config.rules[0].replacement, Replacement::Multiline("a\r\nb\r\nc".to_string()));
+    }
+
+    #[test]
+    fn test_escaped_pipe_in_trigger() {
+        let input = "a\\|b | :c => pipes\n";
+        let config = parse_textra_config(input).expect("Failed to parse escaped pipe");
+        assert_eq!(config.rules[0].triggers, vec!["a|b".to_string(), ":c".to_string()]);
+
+        let serialized = serialize_textra_config(&config);
+        assert_eq!(serialized, input);
+        assert_eq!(parse_textra_config(&serialized).unwrap().rules, config.rules);
+    }
+
+    #[test]
+    fn test_over_length_trigger_is_an_error() {
+        let long = "a".repeat(MAX_TEXT_LENGTH + 1);
+        let input = format!("ok | {long} => too long\n");
+        let error = parse_textra_config(&input).unwrap_err();
+        assert!(error.to_string().contains("the longest that can match is 100"), "{error}");
+
+        let input = "///max_trigger_length:3\nbtw => by the way\nbrb => be right back\n:email => a@xo.rs\n";
+        assert!(parse_textra_config(input).is_err());
+        assert!(parse_textra_config(&input.replace(":email", ":em")).is_ok());
+    }
+
     #[test]
     fn test_parse_multiline_replacement() {
         let input = ":tst => `twinkle twinkle little star,\nhow i wonder what you are`\n";
@@ -256,6 +1176,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_code_content_keeps_blank_lines() {
+        let input = ":run => ```python\n\n    print(1)\n\n```\n";
+        let config = parse_textra_config(input).expect("Failed to parse code replacement");
+
+        assert_eq!(
+            config.rules[0].replacement,
+            Replacement::Code { language: "python".to_string(), content: "\n    print(1)\n\n".to_string() }
+        );
+        assert_eq!(serialize_textra_config(&config), input);
+    }
+
     #[test]
     fn test_parse_code_replacement() {
         let input = ":date => ```javascript\nreturn format.date(date.now(), \"YYYY-MM-DD\");\n```\n";
@@ -0,0 +1,595 @@
+use crate::clipboard;
+use chrono::{DateTime, Local, SecondsFormat, Timelike, Utc};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::ffi::OsString;
+use std::os::windows::ffi::OsStringExt;
+use winapi::um::winbase::{GetComputerNameW, GetUserNameW};
+
+/// The most characters a `{{repeat:...}}` token expands to.
+const MAX_REPEAT_CHARS: usize = 1000;
+
+lazy_static! {
+    static ref TOKEN: Regex = Regex::new(r"\{\{([^{}]+)\}\}").unwrap();
+    static ref PARAM: Regex = Regex::new(r"\{(\d+)\}").unwrap();
+}
+
+/// Where `{{hostname}}`, `{{username}}`, `{{clipboard}}` and `{{env:NAME}}`
+/// come from, so tests can supply their own values.
+pub trait SystemInfo {
+    fn hostname(&self) -> Option<String>;
+    fn username(&self) -> Option<String>;
+    fn clipboard(&self) -> clipboard::Content;
+    fn env_var(&self, name: &str) -> Option<String>;
+}
+
+pub struct WindowsSystemInfo;
+
+impl SystemInfo for WindowsSystemInfo {
+    fn hostname(&self) -> Option<String> {
+        wide_string(|buffer, size| unsafe { GetComputerNameW(buffer, size) })
+    }
+
+    fn username(&self) -> Option<String> {
+        wide_string(|buffer, size| unsafe { GetUserNameW(buffer, size) })
+    }
+
+    fn clipboard(&self) -> clipboard::Content {
+        clipboard::content()
+    }
+
+    fn env_var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+}
+
+/// Calls a Win32 function that fills a UTF-16 buffer and its length.
+fn wide_string(fill: impl Fn(*mut u16, *mut u32) -> i32) -> Option<String> {
+    let mut buffer = [0u16; 257];
+    let mut size = buffer.len() as u32;
+    if fill(buffer.as_mut_ptr(), &mut size) == 0 {
+        return None;
+    }
+    // GetUserNameW counts the terminating null, GetComputerNameW doesn't.
+    let text = &buffer[..(size as usize).min(buffer.len())];
+    let end = text.iter().position(|&c| c == 0).unwrap_or(text.len());
+    Some(OsString::from_wide(&text[..end]).to_string_lossy().into_owned())
+}
+
+/// Expands `{{...}}` tokens such as `{{date}}` inside a replacement. Unknown
+/// tokens are left untouched.
+pub fn process_dynamic_replacement(replacement: &str) -> String {
+    process_dynamic_replacement_at(replacement, Local::now())
+}
+
+pub fn process_dynamic_replacement_at(replacement: &str, now: DateTime<Local>) -> String {
+    process_dynamic_replacement_with(replacement, now, &WindowsSystemInfo)
+}
+
+pub fn process_dynamic_replacement_with(replacement: &str, now: DateTime<Local>, system: &dyn SystemInfo) -> String {
+    let context = TokenContext { now, system };
+    TOKEN
+        .replace_all(replacement, |caps: &Captures| {
+            find_token(caps[1].trim())
+                .and_then(|(token, args)| (token.expand)(args, &context))
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
+}
+
+/// A `{{...}}` token that replacements can contain.
+pub struct Token {
+    /// What the token starts with, e.g. `clipboard` in `{{clipboard:upper}}`.
+    pub name: &'static str,
+    /// How the token is written.
+    pub syntax: &'static str,
+    pub help: &'static str,
+    /// A use of the token, which `textra tokens` expands to show its output.
+    pub example: &'static str,
+    /// Expands the part after `name:`, or returns `None` to leave the token
+    /// as it was written.
+    expand: fn(&str, &TokenContext) -> Option<String>,
+}
+
+struct TokenContext<'a> {
+    now: DateTime<Local>,
+    system: &'a dyn SystemInfo,
+}
+
+/// Every token `process_dynamic_replacement` expands. Key tokens such as
+/// `{{enter}}` aren't here: they're pressed, not typed.
+pub const TOKENS: &[Token] = &[
+    Token {
+        name: "date",
+        syntax: "{{date}}",
+        help: "the current date",
+        example: "{{date}}",
+        expand: expand_date,
+    },
+    Token {
+        name: "time",
+        syntax: "{{time}}",
+        help: "the current time",
+        example: "{{time}}",
+        expand: expand_time,
+    },
+    Token {
+        name: "now",
+        syntax: "{{now}}, {{now:utc}}",
+        help: "an ISO-8601 timestamp, with your timezone offset or in UTC",
+        example: "{{now}}",
+        expand: expand_now,
+    },
+    Token {
+        name: "hostname",
+        syntax: "{{hostname}}",
+        help: "the computer's name",
+        example: "{{hostname}}",
+        expand: expand_hostname,
+    },
+    Token {
+        name: "username",
+        syntax: "{{username}}",
+        help: "your Windows user name",
+        example: "{{username}}",
+        expand: expand_username,
+    },
+    Token {
+        name: "env",
+        syntax: "{{env:NAME}}",
+        help: "the value of an environment variable, or nothing if it isn't set",
+        example: "{{env:USERNAME}}",
+        expand: expand_env,
+    },
+    Token {
+        name: "if",
+        syntax: "{{if:time<HH[:MM]?THEN:ELSE}}",
+        help: "one of two texts depending on the time of day; compare with <, <=, >, >= or =",
+        example: "{{if:time<12?Good morning:Good evening}}",
+        expand: expand_if,
+    },
+    Token {
+        name: "repeat",
+        syntax: "{{repeat:TEXT:COUNT}}",
+        help: "TEXT written COUNT times, up to 1000 characters",
+        example: "{{repeat:-:10}}",
+        expand: expand_repeat,
+    },
+    Token {
+        name: "clipboard",
+        syntax: "{{clipboard}}, {{clipboard:trim|upper|lower|urlencode}}",
+        help: "the text on the clipboard, optionally changed first",
+        example: "{{clipboard:trim}}",
+        expand: expand_clipboard,
+    },
+];
+
+/// The token `text` (what's between the braces) uses, and the part after its
+/// name and colon.
+fn find_token(text: &str) -> Option<(&'static Token, &str)> {
+    TOKENS.iter().find_map(|token| {
+        let name = text.get(..token.name.len()).filter(|name| name.eq_ignore_ascii_case(token.name))?;
+        match &text[name.len()..] {
+            "" => Some((token, "")),
+            rest => rest.strip_prefix(':').map(|args| (token, args)),
+        }
+    })
+}
+
+/// What `token.example` expands to right now.
+pub fn render_example(token: &Token) -> String {
+    process_dynamic_replacement(token.example)
+}
+
+/// `textra tokens`
+pub fn handle_tokens() -> anyhow::Result<()> {
+    for token in TOKENS {
+        minimo::showln!(cyan_bold, token.syntax, gray_dim, " - ", token.help);
+        minimo::showln!(gray_dim, "    ", white_bold, token.example, gray_dim, " types ", green_bold, format!("{:?}", render_example(token)));
+    }
+    Ok(())
+}
+
+fn expand_date(args: &str, context: &TokenContext) -> Option<String> {
+    args.is_empty().then(|| context.now.format("%Y-%m-%d").to_string())
+}
+
+fn expand_time(args: &str, context: &TokenContext) -> Option<String> {
+    args.is_empty().then(|| context.now.format("%H:%M:%S").to_string())
+}
+
+fn expand_now(args: &str, context: &TokenContext) -> Option<String> {
+    match args.to_lowercase().as_str() {
+        "" => Some(context.now.to_rfc3339_opts(SecondsFormat::Secs, false)),
+        "utc" => Some(context.now.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true)),
+        _ => None,
+    }
+}
+
+fn expand_hostname(args: &str, context: &TokenContext) -> Option<String> {
+    args.is_empty().then(|| system_value("hostname", context.system.hostname()))
+}
+
+fn expand_username(args: &str, context: &TokenContext) -> Option<String> {
+    args.is_empty().then(|| system_value("username", context.system.username()))
+}
+
+fn expand_env(args: &str, context: &TokenContext) -> Option<String> {
+    let name = args.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some(context.system.env_var(name).unwrap_or_else(|| {
+        eprintln!("Environment variable {} isn't set, typing nothing", name);
+        String::new()
+    }))
+}
+
+fn expand_if(args: &str, context: &TokenContext) -> Option<String> {
+    Some(conditional(args, context.now))
+}
+
+fn expand_repeat(args: &str, _: &TokenContext) -> Option<String> {
+    Some(repeat(args))
+}
+
+/// An empty clipboard types nothing. One holding something other than text
+/// leaves the token as it was written, so it's clear nothing was pasted.
+fn expand_clipboard(args: &str, context: &TokenContext) -> Option<String> {
+    match context.system.clipboard() {
+        clipboard::Content::Empty => transform_clipboard(args, ""),
+        clipboard::Content::Text(text) => transform_clipboard(args, &text),
+        clipboard::Content::Other => {
+            eprintln!("The clipboard doesn't hold text, leaving {{{{clipboard}}}} as it is");
+            None
+        }
+    }
+}
+
+fn system_value(token: &str, value: Option<String>) -> String {
+    value.unwrap_or_else(|| {
+        eprintln!("Couldn't look up {{{{{}}}}}, typing nothing", token);
+        String::new()
+    })
+}
+
+/// Expands `{{if:time<12?Good morning:Good evening}}`. A malformed
+/// conditional expands to nothing.
+fn conditional(spec: &str, now: DateTime<Local>) -> String {
+    match evaluate_conditional(spec, now) {
+        Some(text) => text.to_string(),
+        None => {
+            eprintln!("Malformed conditional '{{{{if:{}}}}}', typing nothing", spec);
+            String::new()
+        }
+    }
+}
+
+/// Picks a branch of `time OP HH[:MM]?THEN:ELSE`, where OP is one of `<`,
+/// `<=`, `>`, `>=` or `=`.
+fn evaluate_conditional(spec: &str, now: DateTime<Local>) -> Option<&str> {
+    let (condition, branches) = spec.split_once('?')?;
+    let (then, otherwise) = branches.split_once(':')?;
+
+    let condition = condition.trim();
+    if !condition.get(..4)?.eq_ignore_ascii_case("time") {
+        return None;
+    }
+    let comparison = condition[4..].trim_start();
+    let (op, value) = ["<=", ">=", "<", ">", "="]
+        .iter()
+        .find_map(|op| comparison.strip_prefix(op).map(|value| (*op, value)))?;
+    let value = parse_time_of_day(value.trim())?;
+    let current = now.hour() * 60 + now.minute();
+
+    let holds = match op {
+        "<" => current < value,
+        "<=" => current <= value,
+        ">" => current > value,
+        ">=" => current >= value,
+        _ => current == value,
+    };
+    Some(if holds { then } else { otherwise })
+}
+
+/// Parses `HH` or `HH:MM` into minutes since midnight.
+fn parse_time_of_day(value: &str) -> Option<u32> {
+    let (hour, minute) = value.split_once(':').unwrap_or((value, "0"));
+    let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+    (hour < 24 && minute < 60).then(|| hour * 60 + minute)
+}
+
+/// Expands `{{repeat:TEXT:COUNT}}` to TEXT written COUNT times, cut short at
+/// `MAX_REPEAT_CHARS`. A count that isn't a number expands to nothing.
+fn repeat(spec: &str) -> String {
+    match parse_repeat(spec) {
+        Some((text, count)) => {
+            let limit = MAX_REPEAT_CHARS / text.chars().count();
+            if count > limit {
+                eprintln!("Repeat count {} is too large, typing {} instead", count, limit);
+            }
+            text.repeat(count.min(limit))
+        }
+        None => {
+            eprintln!("Malformed repeat '{{{{repeat:{}}}}}', typing nothing", spec);
+            String::new()
+        }
+    }
+}
+
+/// Splits `TEXT:COUNT` at the last colon, so TEXT may contain colons itself.
+fn parse_repeat(spec: &str) -> Option<(&str, usize)> {
+    let (text, count) = spec.rsplit_once(':')?;
+    let count = count.trim();
+    if text.is_empty() || count.is_empty() || !count.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // All digits, so parsing only fails for a count too big to matter.
+    Some((text, count.parse().unwrap_or(usize::MAX)))
+}
+
+/// Applies the transform in `{{clipboard:NAME}}` to the clipboard text, or
+/// `None` if there is no such transform.
+fn transform_clipboard(transform: &str, text: &str) -> Option<String> {
+    match transform.trim().to_lowercase().as_str() {
+        "" => Some(text.to_string()),
+        "trim" => Some(text.trim().to_string()),
+        "upper" => Some(text.to_uppercase()),
+        "lower" => Some(text.to_lowercase()),
+        "urlencode" => Some(url_encode(text)),
+        _ => None,
+    }
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986.
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Fills `{1}`, `{2}`, ... with the arguments of a parameterized snippet.
+/// Missing arguments become empty.
+pub fn substitute_params(replacement: &str, args: &[&str]) -> String {
+    PARAM
+        .replace_all(replacement, |caps: &Captures| {
+            let index: usize = caps[1].parse().unwrap_or(0);
+            index.checked_sub(1).and_then(|i| args.get(i)).map_or("", |arg| arg.trim()).to_string()
+        })
+        .into_owned()
+}
+
+pub fn propagate_case_fn(original: &str, replacement: &str) -> String {
+    if original.chars().all(|c| c.is_uppercase()) {
+        replacement.to_uppercase()
+    } else if original.chars().next().map_or(false, |c| c.is_uppercase()) {
+        let mut chars = replacement.chars();
+        match chars.next() {
+            None => String::new(),
+            Some(first_char) => first_char.to_uppercase().collect::<String>() + chars.as_str(),
+        }
+    } else {
+        replacement.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn fixed_now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 2, 15, 4, 5).unwrap()
+    }
+
+    #[test]
+    fn test_date_and_time_tokens() {
+        let output = process_dynamic_replacement_at("on {{date}} at {{TIME}}", fixed_now());
+        assert_eq!(output, "on 2024-01-02 at 15:04:05");
+    }
+
+    #[test]
+    fn test_now_token_is_rfc3339() {
+        let rfc3339 = Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}([+-]\d{2}:\d{2}|Z)$").unwrap();
+
+        let local = process_dynamic_replacement_at("{{now}}", fixed_now());
+        assert!(rfc3339.is_match(&local), "{local}");
+        assert!(local.starts_with("2024-01-02T15:04:05"));
+
+        let utc = process_dynamic_replacement_at("{{now:utc}}", fixed_now());
+        assert!(rfc3339.is_match(&utc), "{utc}");
+        assert!(utc.ends_with('Z'));
+    }
+
+    struct FakeSystem(Option<&'static str>);
+
+    impl SystemInfo for FakeSystem {
+        fn hostname(&self) -> Option<String> {
+            self.0.map(|_| "BUILD-PC".to_string())
+        }
+
+        fn username(&self) -> Option<String> {
+            self.0.map(str::to_string)
+        }
+
+        fn clipboard(&self) -> clipboard::Content {
+            clipboard::Content::Text(" copied ".to_string())
+        }
+
+        fn env_var(&self, name: &str) -> Option<String> {
+            match name {
+                "USERNAME" => self.0.map(str::to_string),
+                "PROJECT_ROOT" => Some(r"C:\src\textra".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    struct FakeClipboard(clipboard::Content);
+
+    impl SystemInfo for FakeClipboard {
+        fn hostname(&self) -> Option<String> {
+            None
+        }
+
+        fn username(&self) -> Option<String> {
+            None
+        }
+
+        fn clipboard(&self) -> clipboard::Content {
+            self.0.clone()
+        }
+
+        fn env_var(&self, _: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_hostname_and_username_tokens() {
+        let template = "{{username}}@{{Hostname}}";
+        assert_eq!(process_dynamic_replacement_with(template, fixed_now(), &FakeSystem(Some("ada"))), "ada@BUILD-PC");
+        assert_eq!(process_dynamic_replacement_with(template, fixed_now(), &FakeSystem(None)), "@");
+    }
+
+    #[test]
+    fn test_token_registry_renders_examples() {
+        let system = FakeSystem(Some("ada"));
+        for token in TOKENS {
+            let (found, _) = find_token(token.example.trim_start_matches("{{").trim_end_matches("}}")).unwrap();
+            assert_eq!(found.name, token.name);
+
+            let output = process_dynamic_replacement_with(token.example, fixed_now(), &system);
+            assert!(!output.is_empty() && !output.contains("{{"), "{}: {output:?}", token.name);
+        }
+        assert_eq!(process_dynamic_replacement_with("{{if:time<12?Good morning:Good evening}}", fixed_now(), &system), "Good evening");
+    }
+
+    #[test]
+    fn test_find_token_splits_name_and_arguments() {
+        let name_and_args = |text| find_token(text).map(|(token, args)| (token.name, args));
+
+        assert_eq!(name_and_args("date"), Some(("date", "")));
+        assert_eq!(name_and_args("Repeat:-:3"), Some(("repeat", "-:3")));
+        assert_eq!(name_and_args("if:time<12?Morning:Evening"), Some(("if", "time<12?Morning:Evening")));
+        assert_eq!(name_and_args("clipboard:UPPER"), Some(("clipboard", "UPPER")));
+        assert_eq!(name_and_args("dates"), None);
+        assert_eq!(name_and_args("enter"), None);
+    }
+
+    #[test]
+    fn test_token_handlers() {
+        let system = FakeSystem(Some("ada"));
+        let context = TokenContext { now: fixed_now(), system: &system };
+
+        assert_eq!(expand_date("", &context).as_deref(), Some("2024-01-02"));
+        assert_eq!(expand_date("iso", &context), None);
+        assert_eq!(expand_time("", &context).as_deref(), Some("15:04:05"));
+        assert!(expand_now("UTC", &context).unwrap().ends_with('Z'));
+        assert_eq!(expand_username("", &context).as_deref(), Some("ada"));
+        assert_eq!(expand_hostname("", &context).as_deref(), Some("BUILD-PC"));
+        assert_eq!(expand_if("time>=15?late:early", &context).as_deref(), Some("late"));
+        assert_eq!(expand_repeat("ab:2", &context).as_deref(), Some("abab"));
+        assert_eq!(expand_clipboard("trim", &context).as_deref(), Some("copied"));
+        assert_eq!(expand_clipboard("mdlink", &context), None);
+    }
+
+    #[test]
+    fn test_token_names_need_a_colon_before_arguments() {
+        assert_eq!(process_dynamic_replacement_at("{{dates}} {{date:iso}} {{now:mars}}", fixed_now()), "{{dates}} {{date:iso}} {{now:mars}}");
+    }
+
+    #[test]
+    fn test_unknown_token_is_left_alone() {
+        let output = process_dynamic_replacement_at("hello {{mustache}}", fixed_now());
+        assert_eq!(output, "hello {{mustache}}");
+    }
+
+    #[test]
+    fn test_time_conditional() {
+        let greeting = "{{if:time<12?Good morning:Good evening}}";
+        let morning = Local.with_ymd_and_hms(2024, 1, 2, 9, 30, 0).unwrap();
+        let noon = Local.with_ymd_and_hms(2024, 1, 2, 12, 0, 0).unwrap();
+
+        assert_eq!(process_dynamic_replacement_at(greeting, morning), "Good morning");
+        assert_eq!(process_dynamic_replacement_at(greeting, noon), "Good evening");
+        assert_eq!(process_dynamic_replacement_at(greeting, fixed_now()), "Good evening");
+        assert_eq!(process_dynamic_replacement_at("{{if: time >= 15:04 ?late:early}}", fixed_now()), "late");
+    }
+
+    #[test]
+    fn test_malformed_conditional_is_empty() {
+        assert_eq!(process_dynamic_replacement_at("[{{if:time<noon?a:b}}]", fixed_now()), "[]");
+        assert_eq!(process_dynamic_replacement_at("[{{if:weather<12?a:b}}]", fixed_now()), "[]");
+        assert_eq!(process_dynamic_replacement_at("[{{if:time<12 a}}]", fixed_now()), "[]");
+    }
+
+    #[test]
+    fn test_repeat_token() {
+        assert_eq!(process_dynamic_replacement_at("{{repeat:-:5}}", fixed_now()), "-----");
+        assert_eq!(process_dynamic_replacement_at("[{{REPEAT:ab:3}}]", fixed_now()), "[ababab]");
+        assert_eq!(process_dynamic_replacement_at("{{repeat:::2}}", fixed_now()), "::");
+        assert_eq!(process_dynamic_replacement_at("[{{repeat:-:0}}]", fixed_now()), "[]");
+    }
+
+    #[test]
+    fn test_repeat_count_from_parameter() {
+        let replacement = substitute_params("{{repeat:=:{1}}}", &["4"]);
+        assert_eq!(process_dynamic_replacement_at(&replacement, fixed_now()), "====");
+    }
+
+    #[test]
+    fn test_repeat_count_is_clamped() {
+        assert_eq!(process_dynamic_replacement_at("{{repeat:-:5000}}", fixed_now()).len(), MAX_REPEAT_CHARS);
+        assert_eq!(process_dynamic_replacement_at("{{repeat:ab:99999999999999999999999}}", fixed_now()).len(), MAX_REPEAT_CHARS);
+        assert_eq!(process_dynamic_replacement_at("[{{repeat:-:many}}]", fixed_now()), "[]");
+        assert_eq!(process_dynamic_replacement_at("[{{repeat:-:-3}}]", fixed_now()), "[]");
+        assert_eq!(process_dynamic_replacement_at("[{{repeat:-}}]", fixed_now()), "[]");
+    }
+
+    #[test]
+    fn test_env_tokens() {
+        let template = "{{env:USERNAME}} works in {{ENV: PROJECT_ROOT }}{{env:NOT_SET}} as {{env:USERNAME}}";
+        let output = process_dynamic_replacement_with(template, fixed_now(), &FakeSystem(Some("ada")));
+
+        assert_eq!(output, r"ada works in C:\src\textra as ada");
+        assert_eq!(process_dynamic_replacement_at("{{env:}}", fixed_now()), "{{env:}}");
+    }
+
+    #[test]
+    fn test_clipboard_token() {
+        let expand = |content| process_dynamic_replacement_with(":wrap \"{{clipboard}}\" {{clipboard:upper}}", fixed_now(), &FakeClipboard(content));
+
+        assert_eq!(expand(clipboard::Content::Text("hi".to_string())), ":wrap \"hi\" HI");
+        assert_eq!(expand(clipboard::Content::Empty), ":wrap \"\" ");
+        assert_eq!(expand(clipboard::Content::Other), ":wrap \"{{clipboard}}\" {{clipboard:upper}}");
+    }
+
+    #[test]
+    fn test_clipboard_transforms() {
+        let url = "  https://example.com/a b?q=1&r=ü \n";
+        assert_eq!(transform_clipboard("", url).unwrap(), url);
+        assert_eq!(transform_clipboard("trim", url).unwrap(), "https://example.com/a b?q=1&r=ü");
+        assert_eq!(transform_clipboard("upper", "Mixed Case").unwrap(), "MIXED CASE");
+        assert_eq!(transform_clipboard("LOWER", "Mixed Case").unwrap(), "mixed case");
+        assert_eq!(transform_clipboard("urlencode", "a b&c=ü~").unwrap(), "a%20b%26c%3D%C3%BC~");
+        assert_eq!(transform_clipboard("mdlink", url), None);
+    }
+
+    #[test]
+    fn test_substitute_params() {
+        assert_eq!(substitute_params("<a href=\"{1}\">{2}</a>", &["google.com", "Google"]), "<a href=\"google.com\">Google</a>");
+        assert_eq!(substitute_params("{2}-{1}-{3}", &["a", "b"]), "b-a-");
+        assert_eq!(substitute_params("{{date}} {1}", &["x"]), "{{date}} x");
+    }
+
+    #[test]
+    fn test_propagate_case() {
+        assert_eq!(propagate_case_fn("BTW", "by the way"), "BY THE WAY");
+        assert_eq!(propagate_case_fn("Btw", "by the way"), "By the way");
+        assert_eq!(propagate_case_fn("btw", "by the way"), "by the way");
+    }
+}
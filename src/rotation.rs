@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const STATE_FILE_NAME: &str = "rotation.json";
+
+/// Which option each `// Rotate` rule typed last, kept on disk so the order
+/// carries on across restarts and `textra reset-rotation` can clear it.
+#[derive(Debug, Default)]
+pub struct Rotation {
+    path: Option<PathBuf>,
+    next: HashMap<String, usize>,
+}
+
+impl Rotation {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path, next: HashMap::new() }
+    }
+
+    /// Returns the index of the option to type for `trigger` out of `count`,
+    /// and moves on to the next one.
+    pub fn advance(&mut self, trigger: &str, count: usize) -> usize {
+        if let Some(path) = &self.path {
+            // Re-read in case the rotation was reset from the command line.
+            self.next = load(path).unwrap_or_default();
+        }
+        let index = self.next.get(trigger).copied().unwrap_or(0) % count.max(1);
+        self.next.insert(trigger.to_string(), index + 1);
+        if let Some(path) = &self.path {
+            if let Err(e) = save(path, &self.next) {
+                eprintln!("Failed to save rotation state: {}", e);
+            }
+        }
+        index
+    }
+}
+
+/// `rotation.json` in the install directory.
+pub fn state_path() -> Option<PathBuf> {
+    crate::installer::install_dir_path().ok().map(|dir| dir.join(STATE_FILE_NAME))
+}
+
+/// Splits a rotating replacement into its options, which are separated by
+/// lines holding only `---`.
+pub fn options(replacement: &str) -> Vec<&str> {
+    let mut options = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in replacement.split_inclusive('\n') {
+        if line.trim() == "---" {
+            options.push(replacement[start..offset].trim_end_matches(|c| c == '\r' || c == '\n'));
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    options.push(&replacement[start..]);
+    options
+}
+
+pub fn reset(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn load(path: &Path) -> io::Result<HashMap<String, usize>> {
+    let text = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+fn save(path: &Path, next: &HashMap<String, usize>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    crate::config::write_config_atomically(path, &serde_json::to_string(next)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_split_on_marker() {
+        assert_eq!(options("Best,\nA\n---\nCheers,\nA"), vec!["Best,\nA", "Cheers,\nA"]);
+        assert_eq!(options("one\r\n---\r\ntwo"), vec!["one", "two"]);
+        assert_eq!(options("only"), vec!["only"]);
+    }
+
+    #[test]
+    fn test_rotation_cycles_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATE_FILE_NAME);
+        let mut rotation = Rotation::new(Some(path.clone()));
+
+        let picks: Vec<usize> = (0..4).map(|_| rotation.advance(":sig", 3)).collect();
+        assert_eq!(picks, vec![0, 1, 2, 0]);
+        assert_eq!(rotation.advance(":other", 2), 0);
+
+        // A fresh daemon carries on where the last one stopped.
+        assert_eq!(Rotation::new(Some(path.clone())).advance(":sig", 3), 1);
+
+        reset(&path).unwrap();
+        assert_eq!(rotation.advance(":sig", 3), 0);
+    }
+}
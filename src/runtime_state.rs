@@ -0,0 +1,133 @@
+use crate::state::Metrics;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const STATE_FILE_NAME: &str = "runtime_state.json";
+
+/// How often the daemon saves its counters while it runs.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counters the daemon carries across restarts. They are saved every
+/// `SAVE_INTERVAL` and on a clean shutdown rather than on every change, so a
+/// crash loses at most a minute of them.
+///
+/// Rotation indices aren't here: `textra reset-rotation` changes them from
+/// outside the daemon, so they keep their own file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuntimeState {
+    #[serde(default)]
+    pub keystrokes_processed: u64,
+    #[serde(default)]
+    pub expansions_performed: u64,
+    /// Trigger lookups and the time they took in total, which together give
+    /// the average lookup time.
+    #[serde(default)]
+    pub lookups: u64,
+    #[serde(default)]
+    pub lookup_nanos: u64,
+}
+
+impl RuntimeState {
+    pub fn of(metrics: &Metrics) -> Self {
+        Self {
+            keystrokes_processed: metrics.keystrokes_processed.load(Ordering::SeqCst),
+            expansions_performed: metrics.expansions_performed.load(Ordering::SeqCst),
+            lookups: metrics.lookups.load(Ordering::SeqCst),
+            lookup_nanos: metrics.lookup_nanos.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn average_lookup_time(&self) -> Duration {
+        match self.lookups {
+            0 => Duration::ZERO,
+            lookups => Duration::from_nanos(self.lookup_nanos / lookups),
+        }
+    }
+
+    /// Starts `metrics` from the saved counts.
+    pub fn restore(&self, metrics: &Metrics) {
+        metrics.keystrokes_processed.store(self.keystrokes_processed, Ordering::SeqCst);
+        metrics.expansions_performed.store(self.expansions_performed, Ordering::SeqCst);
+        metrics.lookups.store(self.lookups, Ordering::SeqCst);
+        metrics.lookup_nanos.store(self.lookup_nanos, Ordering::SeqCst);
+    }
+}
+
+/// `runtime_state.json` in the install directory.
+pub fn state_path() -> Option<PathBuf> {
+    crate::installer::install_dir_path().ok().map(|dir| dir.join(STATE_FILE_NAME))
+}
+
+/// The saved state, or a fresh one if there is none or it can't be read.
+pub fn load(path: &Path) -> RuntimeState {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+pub fn save(path: &Path, state: &RuntimeState) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    crate::config::write_config_atomically(path, &serde_json::to_string_pretty(state)?)
+}
+
+/// Saves the metrics now, logging rather than failing if that doesn't work.
+pub fn save_metrics(metrics: &Metrics) {
+    if let Some(path) = state_path() {
+        if let Err(e) = save(&path, &RuntimeState::of(metrics)) {
+            eprintln!("Failed to save runtime state: {}", e);
+        }
+    }
+}
+
+/// Saves the metrics every `SAVE_INTERVAL` for as long as the daemon runs.
+pub fn spawn_autosave(metrics: Arc<Metrics>) {
+    thread::spawn(move || loop {
+        thread::sleep(SAVE_INTERVAL);
+        save_metrics(&metrics);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATE_FILE_NAME);
+        assert_eq!(load(&path), RuntimeState::default());
+
+        let metrics = Metrics::default();
+        metrics.record_keystroke();
+        metrics.record_keystroke();
+        metrics.record_expansion();
+        metrics.record_lookup(Duration::from_micros(10));
+        metrics.record_lookup(Duration::from_micros(30));
+        save(&path, &RuntimeState::of(&metrics)).unwrap();
+
+        let restored = Metrics::default();
+        load(&path).restore(&restored);
+        assert_eq!(restored.keystrokes_processed.load(Ordering::SeqCst), 2);
+        assert_eq!(restored.expansions_performed.load(Ordering::SeqCst), 1);
+        assert_eq!(restored.average_lookup_time(), Duration::from_micros(20));
+        assert_eq!(load(&path).average_lookup_time(), Duration::from_micros(20));
+    }
+
+    #[test]
+    fn test_unreadable_state_starts_fresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATE_FILE_NAME);
+        fs::write(&path, "not json").unwrap();
+
+        assert_eq!(load(&path), RuntimeState::default());
+    }
+}
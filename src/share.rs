@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::config::{get_config_path, read_config_file, write_config_atomically};
+use crate::{parse_textra_config, serialize_textra_config, Replacement, TextraConfig, TextraRule, SECRET_MASK};
+
+/// Where `textra share` uploads to unless `share_url` says otherwise. It
+/// takes the text as the request body and answers with the link.
+pub const DEFAULT_SHARE_URL: &str = "https://paste.rs/";
+
+/// Uploads and downloads shared configs, so sharing can be tested offline.
+pub trait PasteClient {
+    fn upload(&self, text: &str) -> Result<String>;
+    fn download(&self, url: &str) -> Result<String>;
+}
+
+pub struct HttpPasteClient {
+    pub endpoint: String,
+}
+
+impl HttpPasteClient {
+    fn client() -> Result<reqwest::blocking::Client> {
+        Ok(reqwest::blocking::Client::builder().timeout(Duration::from_secs(15)).build()?)
+    }
+}
+
+impl PasteClient for HttpPasteClient {
+    fn upload(&self, text: &str) -> Result<String> {
+        let response = Self::client()?
+            .post(&self.endpoint)
+            .header("User-Agent", "Textra")
+            .body(text.to_string())
+            .send()
+            .with_context(|| format!("Couldn't reach {}", self.endpoint))?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("{} refused the upload: {}", self.endpoint, response.status()));
+        }
+        Ok(response.text()?.trim().to_string())
+    }
+
+    fn download(&self, url: &str) -> Result<String> {
+        let response = Self::client()?
+            .get(url)
+            .header("User-Agent", "Textra")
+            .send()
+            .with_context(|| format!("Couldn't reach {}", url))?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Couldn't download {}: {}", url, response.status()));
+        }
+        Ok(response.text()?)
+    }
+}
+
+/// The rules of `config` as they're shared: without `// Private` rules,
+/// with `// Secret` ones masked, and without settings, which belong to
+/// whoever shares them.
+pub fn redact(config: &TextraConfig) -> TextraConfig {
+    let mut redacted = TextraConfig {
+        metadata: HashMap::new(),
+        documentation: Vec::new(),
+        rules: config
+            .rules
+            .iter()
+            .filter(|rule| !rule.options.private)
+            .map(|rule| {
+                let mut rule = rule.clone();
+                if rule.options.secret {
+                    rule.replacement = Replacement::Simple(SECRET_MASK.to_string());
+                }
+                rule
+            })
+            .collect(),
+        settings: Default::default(),
+        abbreviations: HashMap::new(),
+        matcher: Default::default(),
+    };
+    redacted.index_triggers();
+    redacted
+}
+
+pub fn share(config: &TextraConfig, client: &impl PasteClient) -> Result<String> {
+    client.upload(&serialize_textra_config(&redact(config)))
+}
+
+/// Downloads a shared config and returns its rules whose triggers aren't in
+/// `existing` yet, along with how many were skipped as duplicates.
+pub fn fetch_import(existing: &TextraConfig, url: &str, client: &impl PasteClient) -> Result<(Vec<TextraRule>, usize)> {
+    let text = client.download(url)?;
+    let imported = parse_textra_config(&text).map_err(|e| anyhow::anyhow!("{} isn't a valid config:\n{}", url, e))?;
+    let known: Vec<&String> = existing.rules.iter().flat_map(|rule| &rule.triggers).collect();
+    let total = imported.rules.len();
+    let fresh: Vec<TextraRule> = imported
+        .rules
+        .into_iter()
+        .filter(|rule| !rule.triggers.iter().any(|trigger| known.contains(&trigger)))
+        .collect();
+    let skipped = total - fresh.len();
+    Ok((fresh, skipped))
+}
+
+pub fn handle_share() -> Result<()> {
+    let config = crate::load_config().map_err(|e| anyhow::anyhow!("Your config has errors:\n{}", e))?;
+    let client = HttpPasteClient {
+        endpoint: config.settings.share_url.clone().unwrap_or_else(|| DEFAULT_SHARE_URL.to_string()),
+    };
+    let private = config.rules.len() - redact(&config).rules.len();
+    let link = share(&config, &client)?;
+    minimo::showln!(gray_dim, "shared ", yellow_bold, (config.rules.len() - private).to_string(), gray_dim, " rules: ", green_bold, link);
+    if private > 0 {
+        minimo::showln!(gray_dim, format!("left out {} private rules.", private));
+    }
+    Ok(())
+}
+
+pub fn handle_import(url: &str) -> Result<()> {
+    let config_path = get_config_path()?;
+    let text = read_config_file(&config_path)?;
+    let existing = parse_textra_config(&text).map_err(|e| anyhow::anyhow!("Your config has errors:\n{}", e))?;
+    let client = HttpPasteClient { endpoint: url.to_string() };
+    let (mut rules, skipped) = fetch_import(&existing, url, &client)?;
+
+    let code_rules = rules.iter().filter(|rule| matches!(rule.replacement, Replacement::Code { .. })).count();
+    if code_rules > 0 && !confirm(&format!("{} of these rules run code on your computer. Import them too? [y/N] ", code_rules))? {
+        rules.retain(|rule| !matches!(rule.replacement, Replacement::Code { .. }));
+    }
+    if rules.is_empty() {
+        minimo::showln!(gray_dim, "nothing new to import.");
+        return Ok(());
+    }
+
+    let mut imported = redact(&existing);
+    imported.rules = rules;
+    imported.index_triggers();
+    let separator = if text.ends_with('\n') || text.is_empty() { "" } else { "\n" };
+    // The blank line keeps the header from reading as the first rule's description.
+    let updated = format!("{}{}\n// Imported from {}\n\n{}", text, separator, url, serialize_textra_config(&imported));
+    write_config_atomically(&config_path, &updated)?;
+    minimo::showln!(gray_dim, "imported ", green_bold, imported.rules.len().to_string(), gray_dim, " rules.");
+    if skipped > 0 {
+        minimo::showln!(gray_dim, format!("skipped {} rules whose triggers you already use.", skipped));
+    }
+    Ok(())
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct FakePasteClient {
+        uploaded: RefCell<Vec<String>>,
+        served: String,
+    }
+
+    impl PasteClient for FakePasteClient {
+        fn upload(&self, text: &str) -> Result<String> {
+            self.uploaded.borrow_mut().push(text.to_string());
+            Ok("https://paste.example/abc".to_string())
+        }
+
+        fn download(&self, _url: &str) -> Result<String> {
+            Ok(self.served.clone())
+        }
+    }
+
+    fn config(input: &str) -> TextraConfig {
+        parse_textra_config(input).expect("Failed to parse test config")
+    }
+
+    #[test]
+    fn test_redact_drops_private_rules_and_settings() {
+        let config = config("///cancel_key:f12\nbtw => by the way\n// Private\n:addr => 1 Main Street\n// Secret\n:pw => hunter2\n");
+        let redacted = redact(&config);
+
+        assert_eq!(redacted.rules.len(), 2);
+        assert_eq!(serialize_textra_config(&redacted), "btw => by the way\n// Secret\n:pw => ***\n");
+    }
+
+    #[test]
+    fn test_share_uploads_redacted_config() {
+        let client = FakePasteClient::default();
+        let config = config("// Private\n:pw => hunter2\nbtw => by the way\n");
+
+        assert_eq!(share(&config, &client).unwrap(), "https://paste.example/abc");
+        assert_eq!(client.uploaded.borrow().as_slice(), ["btw => by the way\n"]);
+    }
+
+    #[test]
+    fn test_import_skips_known_triggers() {
+        let client = FakePasteClient {
+            served: "btw => by the way\nomw | :omw => on my way\n".to_string(),
+            ..Default::default()
+        };
+        let existing = config(":omw => on my way!\n");
+
+        let (rules, skipped) = fetch_import(&existing, "https://paste.example/abc", &client).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].triggers, vec!["btw".to_string()]);
+        assert_eq!(skipped, 1);
+
+        let client = FakePasteClient { served: "not a config".to_string(), ..Default::default() };
+        assert!(fetch_import(&existing, "https://paste.example/abc", &client).is_err());
+    }
+}
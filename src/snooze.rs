@@ -0,0 +1,110 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Local};
+
+const STATE_FILE_NAME: &str = "snooze";
+
+/// `snooze` in the install directory. It holds the time expansions turn back
+/// on, so `textra snooze` works without talking to the running service.
+pub fn state_path() -> Option<PathBuf> {
+    crate::installer::install_dir_path().ok().map(|dir| dir.join(STATE_FILE_NAME))
+}
+
+/// Parses `30m`, `2h`, `45s` or a bare number of minutes.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim().to_lowercase();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text.as_str(), "m"),
+    };
+    let number: i64 = number.parse().ok()?;
+    match unit {
+        "s" => Some(Duration::seconds(number)),
+        "m" | "min" => Some(Duration::minutes(number)),
+        "h" => Some(Duration::hours(number)),
+        _ => None,
+    }
+}
+
+pub fn snooze(path: &Path, duration: Duration) -> io::Result<DateTime<Local>> {
+    snooze_at(path, duration, Local::now())
+}
+
+pub fn snooze_at(path: &Path, duration: Duration, now: DateTime<Local>) -> io::Result<DateTime<Local>> {
+    let until = now + duration;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    crate::config::write_config_atomically(path, &until.to_rfc3339())?;
+    Ok(until)
+}
+
+/// Turns expansions back on straight away.
+pub fn wake(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// How long expansions stay off, or `None` when they're on.
+pub fn remaining(path: &Path) -> Option<Duration> {
+    remaining_at(path, Local::now())
+}
+
+pub fn remaining_at(path: &Path, now: DateTime<Local>) -> Option<Duration> {
+    let text = fs::read_to_string(path).ok()?;
+    let until = DateTime::parse_from_rfc3339(text.trim()).ok()?;
+    let left = until.with_timezone(&Local) - now;
+    (left > Duration::zero()).then_some(left)
+}
+
+pub fn format_remaining(left: Duration) -> String {
+    let minutes = (left.num_seconds() + 59) / 60;
+    if minutes >= 60 {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+        assert_eq!(parse_duration("45s"), Some(Duration::seconds(45)));
+        assert_eq!(parse_duration("15"), Some(Duration::minutes(15)));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("5d"), None);
+    }
+
+    #[test]
+    fn test_snooze_expires_and_wakes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(STATE_FILE_NAME);
+        let now = Local::now();
+
+        assert_eq!(remaining_at(&path, now), None);
+
+        snooze_at(&path, Duration::minutes(30), now).unwrap();
+        assert_eq!(remaining_at(&path, now + Duration::minutes(10)), Some(Duration::minutes(20)));
+        assert_eq!(remaining_at(&path, now + Duration::minutes(30)), None);
+        assert_eq!(remaining_at(&path, now + Duration::hours(1)), None);
+
+        snooze_at(&path, Duration::minutes(30), now).unwrap();
+        wake(&path).unwrap();
+        assert_eq!(remaining_at(&path, now), None);
+    }
+
+    #[test]
+    fn test_format_remaining() {
+        assert_eq!(format_remaining(Duration::seconds(90)), "2m");
+        assert_eq!(format_remaining(Duration::minutes(125)), "2h 5m");
+    }
+}
@@ -1,4 +1,5 @@
 use super::*;
+use crate::rotation::{self, Rotation};
 use anyhow::Result;
 use chrono::Local;
 use notify::{RecursiveMode, Watcher};
@@ -8,7 +9,7 @@ use std::os::windows::ffi::OsStrExt;
 use std::path::Path;
 use std::process::Command;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     Arc, Mutex,
 };
 use std::thread;
@@ -23,6 +24,39 @@ use winapi::um::{libloaderapi::GetModuleHandleW, winuser::*};
 
 pub const MAX_TEXT_LENGTH: usize = 100;
 
+/// Throughput counters. They are atomics so the keyboard hook can update them
+/// without taking a lock.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub keystrokes_processed: AtomicU64,
+    pub expansions_performed: AtomicU64,
+    pub lookups: AtomicU64,
+    pub lookup_nanos: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_keystroke(&self) {
+        self.keystrokes_processed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn record_expansion(&self) {
+        self.expansions_performed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records how long one `find_replacement` call took.
+    pub fn record_lookup(&self, elapsed: Duration) {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        self.lookup_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    pub fn average_lookup_time(&self) -> Duration {
+        match self.lookups.load(Ordering::SeqCst) {
+            0 => Duration::ZERO,
+            lookups => Duration::from_nanos(self.lookup_nanos.load(Ordering::SeqCst) / lookups),
+        }
+    }
+}
+
 pub struct AppState {
     pub config: Arc<Mutex<TextraConfig>>,
     pub current_text: Arc<Mutex<VecDeque<char>>>,
@@ -32,14 +66,30 @@ pub struct AppState {
     pub alt_pressed: Arc<AtomicBool>,
     pub caps_lock_on: Arc<AtomicBool>,
     pub killswitch: Arc<AtomicBool>,
+    pub safe_mode: Arc<AtomicBool>,
     pub overlay_hwnd: Arc<Mutex<HWND>>,
+    pub metrics: Arc<Metrics>,
+    pub rotation: Arc<Mutex<Rotation>>,
+    /// The focused window's keyboard layout (an `HKL`) when the last key was
+    /// pressed, or `0` before the first key.
+    pub keyboard_layout: Arc<AtomicUsize>,
+    /// The window that had focus when the last key was pressed, or `0`
+    /// before the first key.
+    pub focused_window: Arc<AtomicUsize>,
 }
 
 impl AppState {
+    /// Loads the config and the counters saved by the last run.
     pub fn new() -> Result<Self> {
-        let config = load_config()?;
+        let state = Self::with_config(load_config()?);
+        if let Some(path) = crate::runtime_state::state_path() {
+            crate::runtime_state::load(&path).restore(&state.metrics);
+        }
+        Ok(state)
+    }
 
-        Ok(Self {
+    pub fn with_config(config: TextraConfig) -> Self {
+        Self {
             config: Arc::new(Mutex::new(config)),
             current_text: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_TEXT_LENGTH))),
             last_key_time: Arc::new(Mutex::new(Instant::now())),
@@ -48,8 +98,61 @@ impl AppState {
             alt_pressed: Arc::new(AtomicBool::new(false)),
             caps_lock_on: Arc::new(AtomicBool::new(false)),
             killswitch: Arc::new(AtomicBool::new(false)),
+            safe_mode: Arc::new(AtomicBool::new(false)),
             overlay_hwnd: Arc::new(Mutex::new(ptr::null_mut())),
-        })
+            metrics: Arc::new(Metrics::default()),
+            rotation: Arc::new(Mutex::new(Rotation::new(rotation::state_path()))),
+            keyboard_layout: Arc::new(AtomicUsize::new(0)),
+            focused_window: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Forgets the typed text and modifier state, which can be stale after
+    /// the machine wakes up because key-up events were missed.
+    pub fn reset_input_state(&self) {
+        self.current_text.lock().unwrap().clear();
+        self.shift_pressed.store(false, Ordering::SeqCst);
+        self.ctrl_pressed.store(false, Ordering::SeqCst);
+        self.alt_pressed.store(false, Ordering::SeqCst);
+        self.killswitch.store(false, Ordering::SeqCst);
+        let caps_lock_on = unsafe { GetKeyState(VK_CAPITAL) } & 1 != 0;
+        self.caps_lock_on.store(caps_lock_on, Ordering::SeqCst);
+    }
+
+    /// Records the keyboard layout a key was typed with. After a switch the
+    /// same keys type different characters, so the typed text is dropped
+    /// rather than matched against triggers half in one layout and half in
+    /// another. Returns whether the layout changed.
+    pub fn note_keyboard_layout(&self, layout: usize) -> bool {
+        let previous = self.keyboard_layout.swap(layout, Ordering::SeqCst);
+        let changed = previous != 0 && previous != layout;
+        if changed {
+            self.current_text.lock().unwrap().clear();
+        }
+        changed
+    }
+
+    /// Records the window a key was typed into. If it isn't the one the
+    /// earlier keys went to, deleting a trigger would erase text in the
+    /// wrong place, so the typed text is dropped when `reset_on_click` is on.
+    /// Returns whether it was dropped.
+    pub fn note_focused_window(&self, window: usize) -> bool {
+        let previous = self.focused_window.swap(window, Ordering::SeqCst);
+        previous != 0 && previous != window && self.forget_after_cursor_move()
+    }
+
+    /// Drops the typed text after a mouse click, which may have moved the
+    /// cursor away from it. Returns whether it was dropped.
+    pub fn note_click(&self) -> bool {
+        self.forget_after_cursor_move()
+    }
+
+    fn forget_after_cursor_move(&self) -> bool {
+        let reset_on_click = self.config.lock().unwrap().settings.reset_on_click;
+        if reset_on_click {
+            self.current_text.lock().unwrap().clear();
+        }
+        reset_on_click
     }
 
     pub fn get_overlay_hwnd(&self) -> HWND {
@@ -60,18 +163,6 @@ impl AppState {
         *self.overlay_hwnd.lock().unwrap() = hwnd;
     }
 
-    pub fn get_current_status(&self) -> String {
-        let current_text: String = self.current_text.lock().unwrap().iter().collect();
-        format!(
-            "Buffer: {}\nCtrl: {}\nShift: {}\nAlt: {}\nCaps Lock: {}",
-            current_text,
-            self.ctrl_pressed.load(Ordering::SeqCst),
-            self.shift_pressed.load(Ordering::SeqCst),
-            self.alt_pressed.load(Ordering::SeqCst),
-            self.caps_lock_on.load(Ordering::SeqCst)
-        )
-    }
-
     pub fn get_alt_pressed(&self) -> bool {
         self.alt_pressed.load(Ordering::SeqCst)
     }
@@ -98,4 +189,29 @@ impl AppState {
     pub fn get_killswitch(&self) -> bool {
         self.killswitch.load(Ordering::SeqCst)
     }
+
+    pub fn get_safe_mode(&self) -> bool {
+        self.safe_mode.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_count_up() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.average_lookup_time(), Duration::ZERO);
+
+        metrics.record_keystroke();
+        metrics.record_keystroke();
+        metrics.record_expansion();
+        metrics.record_lookup(Duration::from_micros(10));
+        metrics.record_lookup(Duration::from_micros(30));
+
+        assert_eq!(metrics.keystrokes_processed.load(Ordering::SeqCst), 2);
+        assert_eq!(metrics.expansions_performed.load(Ordering::SeqCst), 1);
+        assert_eq!(metrics.average_lookup_time(), Duration::from_micros(20));
+    }
 }